@@ -0,0 +1,73 @@
+use crate::Sample;
+
+/// The point being generated, as the closures set by
+/// `Builder::gen_rand_fn_with_context`,
+/// `calc_roughness_fn_with_context` and
+/// `change_calced_h_fn_with_context` see it.
+///
+/// # Examples
+///
+/// ```
+/// use lazy_diamond_square::{Builder, Context};
+///
+/// // A rougher map at the coarse levels, without cliffs higher
+/// // than 0.1 at the finest ones.
+/// let mut map = Builder::new(65, 0.3)
+///     .seed("qwerty")
+///     .calc_roughness_fn_with_context(Box::new(|c: &Context, r| if c.level < 2 { 0.6 } else { r }))
+///     .change_calced_h_fn_with_context(Box::new(|c: &Context, h| {
+///         if c.step == 1 {
+///             h.clamp(c.mean - 0.1, c.mean + 0.1)
+///         } else {
+///             h
+///         }
+///     }))
+///     .build();
+///
+/// assert!(map.gen(10, 10).is_some());
+/// ```
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct Context<H: Sample = f32> {
+    /// The coordinates of the point. With `InitBy::SquareSquare`
+    /// the points of the levels before the last one lie between
+    /// the points of the map, at `index * step - (step - 1) / 2`,
+    /// and these are the coordinates of the nearest point of the
+    /// map to the top left, which may be outside the map by up to
+    /// half the step.
+    pub x: i64,
+    pub y: i64,
+    /// The indices of the point in its level of
+    /// `InitBy::SquareSquare`, which are unique in the level. For
+    /// the diamond-square algorithm these are the coordinates.
+    pub index: (i64, i64),
+    /// The level of the subdivision: `0` for the biggest step,
+    /// growing by one each time the step halves.
+    pub level: u32,
+    /// The distance between the point and its parents along an
+    /// axis. For `InitBy::SquareSquare` it is the spacing of the
+    /// level in the coordinates of the map.
+    pub step: i64,
+    /// The step of the algorithm, which generates the point.
+    pub kind: Step,
+    /// The heights of the parents. A parent outside the map is
+    /// replaced as the edge mode says. For `InitBy::SquareSquare`
+    /// these are the nearest parent, the two parents next to it
+    /// along `y` and `x`, and the farthest one.
+    pub parents: [H; 4],
+    /// The mean of the parents, which is displaced by the random
+    /// number. For `InitBy::SquareSquare` the parents are
+    /// weighted 9:3:3:1.
+    pub mean: H,
+}
+
+/// This is an enumeration with the steps of the algorithms.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Step {
+    /// The centre of a square of the diamond-square algorithm.
+    Diamond,
+    /// The middle of an edge of the diamond-square algorithm.
+    Square,
+    /// A point of the square-square subdivision.
+    SquareSquare,
+}
@@ -0,0 +1,143 @@
+use std::f64::consts::TAU;
+use std::fmt;
+use std::sync::Arc;
+
+use tinyrand::{Rand, StdRand};
+
+/// The type of the inverse CDF of `Distribution::Custom`.
+pub type InverseCdf = dyn Fn(f32) -> f32 + Send + Sync;
+
+/// This is an enumeration with possible distributions of the
+/// random term of a new point. A distribution gives a signed
+/// value, which is mostly in the range `-1.0..=1.0`:
+/// `Displacement::Classic` multiplies it by the amplitude of the
+/// level, `Displacement::Blend` maps the range `-1.0..=1.0` onto
+/// the unit range of the sample type and clamps the value to it,
+/// unless the map is unbounded.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use lazy_diamond_square::{Builder, Displacement, Distribution};
+///
+/// let mut gaussian = Builder::new(65, 0.5)
+///     .displacement(Displacement::Classic { hurst: 0.8 })
+///     .distribution(Distribution::Gaussian { std_dev: 0.3 })
+///     .seed("qwerty")
+///     .build();
+/// // The values are mostly small, but sometimes close to ±1.
+/// let mut cubic = Builder::new(65, 0.5)
+///     .distribution(Distribution::Custom(Arc::new(|u| (u * 2.0 - 1.0).powi(3))))
+///     .seed("qwerty")
+///     .build();
+///
+/// assert!(gaussian.gen(10, 10).is_some());
+/// assert!(cubic.gen(10, 10).is_some());
+/// ```
+#[derive(Clone, Default)]
+pub enum Distribution {
+    /// Uniform in the range `-1.0..=1.0`.
+    #[default]
+    Uniform,
+    /// Normal with the mean `0.0` and the standard deviation
+    /// `std_dev`. With `std_dev` equal to `1.0 / 3.0` the value
+    /// is in the range `-1.0..=1.0` in 99.7% of cases.
+    Gaussian { std_dev: f32 },
+    /// The value is the inverse CDF (quantile function) applied
+    /// to a uniform random number in the range `0.0..1.0`
+    /// (neither of the bounds is ever passed).
+    Custom(Arc<InverseCdf>),
+}
+
+impl Distribution {
+    /// Draws a value from the random number generator of a
+    /// point.
+    pub(crate) fn sample(&self, rand: &mut StdRand) -> f32 {
+        match self {
+            Distribution::Uniform => rand.next_u16() as f32 / u16::MAX as f32 * 2.0 - 1.0,
+            Distribution::Gaussian { std_dev } => {
+                // The Box-Muller transform.
+                let u1 = open_unit(rand.next_u32());
+                let u2 = open_unit(rand.next_u32());
+                let z = (-2.0 * u1.ln()).sqrt() * (TAU * u2).cos();
+
+                (z * *std_dev as f64) as f32
+            }
+            Distribution::Custom(inverse_cdf) => inverse_cdf(open_unit_f32(rand.next_u32())),
+        }
+    }
+}
+
+impl fmt::Debug for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Distribution::Uniform => f.write_str("Uniform"),
+            Distribution::Gaussian { std_dev } => f
+                .debug_struct("Gaussian")
+                .field("std_dev", std_dev)
+                .finish(),
+            Distribution::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Maps `u32` onto the range `0.0..1.0` without the bounds.
+fn open_unit(n: u32) -> f64 {
+    (n as f64 + 0.5) / (u32::MAX as f64 + 1.0)
+}
+
+/// The same as `open_unit`, but for `f32`, which would round the
+/// biggest values up to `1.0`.
+fn open_unit_f32(n: u32) -> f32 {
+    (open_unit(n) as f32).clamp(f32::MIN_POSITIVE, 1.0 - f32::EPSILON / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tinyrand::Seeded;
+
+    fn moments(distribution: &Distribution) -> (f64, f64) {
+        let mut rand = StdRand::seed(42);
+        let values: Vec<f64> = (0..100_000)
+            .map(|_| distribution.sample(&mut rand) as f64)
+            .collect();
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+
+        (mean, var.sqrt())
+    }
+
+    #[test]
+    fn moments_of_distributions() {
+        let (mean, std_dev) = moments(&Distribution::Uniform);
+        assert!(mean.abs() < 0.01);
+        assert!((std_dev - 1.0 / 3f64.sqrt()).abs() < 0.01);
+
+        let (mean, std_dev) = moments(&Distribution::Gaussian { std_dev: 0.25 });
+        assert!(mean.abs() < 0.01);
+        assert!((std_dev - 0.25).abs() < 0.01);
+
+        let (mean, std_dev) = moments(&Distribution::Custom(Arc::new(|u| u)));
+        assert!((mean - 0.5).abs() < 0.01);
+        assert!((std_dev - 1.0 / 12f64.sqrt()).abs() < 0.01);
+    }
+
+    #[test]
+    fn open_unit_bounds() {
+        assert!(open_unit(0) > 0.0);
+        assert!(open_unit(u32::MAX) < 1.0);
+
+        for n in [0, 1, u32::MAX - 200, u32::MAX - 1, u32::MAX] {
+            assert!(open_unit_f32(n) > 0.0 && open_unit_f32(n) < 1.0, "{n}");
+        }
+        assert!((-open_unit_f32(u32::MAX)).ln_1p().is_finite());
+        assert_eq!(
+            format!("{:?}", Distribution::Gaussian { std_dev: 0.5 }),
+            "Gaussian { std_dev: 0.5 }"
+        );
+    }
+}
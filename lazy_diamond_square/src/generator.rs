@@ -0,0 +1,260 @@
+use std::fmt;
+use std::ops::Deref;
+
+use crate::{HeightMap, Sample, Storage};
+
+/// The rule, by which the missing heights of a `HeightMap` are
+/// generated. A height is calculated from the heights of its
+/// parents, which are generated first, if they are missing, so
+/// a generator only has to say which points a point depends on
+/// and how. The storage, `gen`, `gen_area`, `get_img` and the
+/// shared maps work with any generator.
+///
+/// Without a generator set by `Builder::generator` the map is
+/// generated by `DiamondSquare`, or by the square-square
+/// subdivision with `InitBy::SquareSquare`.
+///
+/// The generator gets the map as a `MapView`, whose `get`
+/// returns the heights already generated, including the ones
+/// generated by the same call to `gen`.
+///
+/// # Examples
+///
+/// ```
+/// use lazy_diamond_square::{Builder, Generator, InitBy, MapView};
+///
+/// /// Each point is a bit higher than its left neighbour.
+/// #[derive(Debug)]
+/// struct Ramp;
+///
+/// impl Generator for Ramp {
+///     fn parents(&self, _map: &MapView, x: i64, y: i64) -> Option<Vec<(i64, i64)>> {
+///         Some(if x > 0 { vec![(x - 1, y)] } else { vec![] })
+///     }
+///
+///     fn calc(&self, _map: &MapView, _x: i64, _y: i64, heights: &[f32]) -> f32 {
+///         heights.first().map_or(0.0, |h| h + 0.01)
+///     }
+/// }
+///
+/// let mut map = Builder::new(65, 0.0)
+///     .init_by(InitBy::None)
+///     .generator(Box::new(Ramp))
+///     .build();
+///
+/// assert!((map.gen(10, 5).unwrap() - 0.1).abs() < 1e-6);
+/// assert!(map.get(3, 5).is_some());
+/// ```
+pub trait Generator<H: Sample = f32>: fmt::Debug + Send + Sync {
+    /// Returns the parents of the point `(x, y)`, whose heights
+    /// are passed to `calc`, or `None` if the point cannot be
+    /// generated. The coordinates outside the map are changed to
+    /// valid coordinates. If an ancestor of the point is the point
+    /// itself, the point is not generated, and `HeightMap::gen`
+    /// returns `None`.
+    fn parents(&self, map: &MapView<H>, x: i64, y: i64) -> Option<Vec<(i64, i64)>>;
+    /// Returns the height of the point `(x, y)`. `heights` are
+    /// the heights of the parents in the order of `parents`.
+    fn calc(&self, map: &MapView<H>, x: i64, y: i64, heights: &[H]) -> H;
+    /// Generates the missing points of `points`, which are in
+    /// valid coordinates and unique, and their missing ancestors.
+    /// It is called by `HeightMap::gen_area` and at
+    /// initialization, and by default generates the points one by
+    /// one by `HeightMap::gen`. A generator, which can generate
+    /// many points at once faster, overrides it.
+    fn gen_points(&self, map: &mut HeightMap<H>, points: &[(i64, i64)]) {
+        for &(x, y) in points {
+            map.gen(x, y);
+        }
+    }
+}
+
+/// A `HeightMap`, as a `Generator` sees it: the settings of the
+/// map, which are available through `Deref`, and the heights of
+/// its cells, which are being generated.
+#[derive(Clone, Copy)]
+pub struct MapView<'a, H: Sample = f32> {
+    map: &'a HeightMap<H>,
+    cells: &'a dyn Storage<H>,
+}
+
+impl<'a, H: Sample> MapView<'a, H> {
+    pub(crate) fn new(map: &'a HeightMap<H>, cells: &'a dyn Storage<H>) -> Self {
+        Self { map, cells }
+    }
+    /// The same as `HeightMap::get`, but the heights generated
+    /// by the current call to `gen` are returned too.
+    pub fn get(&self, x: i64, y: i64) -> Option<H> {
+        let (x, y) = self.map.to_valid_coords(x, y);
+
+        self.cells.get(x, y)
+    }
+}
+
+impl<H: Sample> Deref for MapView<'_, H> {
+    type Target = HeightMap<H>;
+
+    fn deref(&self) -> &HeightMap<H> {
+        self.map
+    }
+}
+
+impl<H: Sample> fmt::Debug for MapView<'_, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MapView")
+            .field("map", self.map)
+            .field("cells", &self.cells)
+            .finish()
+    }
+}
+
+/// The diamond-square algorithm, the default `Generator`. It
+/// uses the settings of the map, such as the roughness, the
+/// edge mode and the callbacks. The points of `gen_points` are
+/// generated level by level, in parallel with the `rayon`
+/// feature.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DiamondSquare;
+
+impl<H: Sample> Generator<H> for DiamondSquare {
+    fn parents(&self, map: &MapView<H>, x: i64, y: i64) -> Option<Vec<(i64, i64)>> {
+        if map.is_corner(x, y) {
+            return None;
+        }
+
+        Some((0..4).filter_map(|index| map.parent(x, y, index)).collect())
+    }
+
+    fn calc(&self, map: &MapView<H>, x: i64, y: i64, heights: &[H]) -> H {
+        let mut present = heights.iter().copied();
+        let heights =
+            [0, 1, 2, 3].map(|index| map.parent(x, y, index).and_then(|_| present.next()));
+
+        map.calc_h(x, y, map.fill_heights(heights))
+    }
+
+    fn gen_points(&self, map: &mut HeightMap<H>, points: &[(i64, i64)]) {
+        map.gen_passes(points);
+    }
+}
+
+/// The square-square subdivision of `InitBy::SquareSquare`. The
+/// parents of a point of the map are the points of the previous
+/// level of the subdivision, which are kept aside from the
+/// storage, so they are looked up by `calc` itself.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct SquareSquare;
+
+impl<H: Sample> Generator<H> for SquareSquare {
+    fn parents(&self, _map: &MapView<H>, _x: i64, _y: i64) -> Option<Vec<(i64, i64)>> {
+        Some(Vec::new())
+    }
+
+    fn calc(&self, map: &MapView<H>, x: i64, y: i64, _heights: &[H]) -> H {
+        map.square_square(map.max_coord().trailing_zeros(), x, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Builder, EdgeMode, InitBy};
+
+    #[test]
+    fn diamond_square() {
+        /// `DiamondSquare`, which generates the points of an area
+        /// one by one.
+        #[derive(Debug)]
+        struct PointByPoint;
+
+        impl Generator for PointByPoint {
+            fn parents(&self, map: &MapView, x: i64, y: i64) -> Option<Vec<(i64, i64)>> {
+                DiamondSquare.parents(map, x, y)
+            }
+
+            fn calc(&self, map: &MapView, x: i64, y: i64, heights: &[f32]) -> f32 {
+                DiamondSquare.calc(map, x, y, heights)
+            }
+        }
+
+        for mode in [EdgeMode::Wrap, EdgeMode::Clamp] {
+            let builder = Builder::new(65, 0.3)
+                .seed("diamond_square")
+                .edge_mode(mode)
+                .init_lvl(3);
+            let mut by_levels = builder.clone().build();
+            let mut by_point = builder.generator(Box::new(PointByPoint)).build();
+
+            assert_eq!(format!("{:?}", by_levels.generator()), "DiamondSquare");
+            assert_eq!(
+                by_point.gen_area((0, 0), (64, 64)),
+                by_levels.gen_area((0, 0), (64, 64))
+            );
+        }
+    }
+
+    #[test]
+    fn neighbours() {
+        /// Each point is a bit higher than its left neighbour, if
+        /// it is generated already.
+        #[derive(Debug)]
+        struct Left;
+
+        impl Generator for Left {
+            fn parents(&self, _map: &MapView, _x: i64, _y: i64) -> Option<Vec<(i64, i64)>> {
+                Some(Vec::new())
+            }
+
+            fn calc(&self, map: &MapView, x: i64, y: i64, _heights: &[f32]) -> f32 {
+                map.get(x - 1, y).map_or(-1.0, |h| h + 0.25)
+            }
+        }
+
+        let builder = Builder::new(17, 0.0)
+            .init_by(InitBy::None)
+            .generator(Box::new(Left));
+        let mut map = builder.clone().build();
+        let shared = builder.build_shared();
+
+        map.set(4, 4, Some(0.5));
+        shared.set(4, 4, Some(0.5));
+
+        assert_eq!(map.gen(5, 4), Some(0.75));
+        assert_eq!(shared.gen(5, 4), Some(0.75));
+        assert_eq!(map.gen(7, 4), Some(-1.0));
+    }
+
+    #[test]
+    fn cycle() {
+        /// Each point depends on its right neighbour, so the
+        /// points of a row depend on each other.
+        #[derive(Debug)]
+        struct Cycle;
+
+        impl Generator for Cycle {
+            fn parents(&self, _map: &MapView, x: i64, y: i64) -> Option<Vec<(i64, i64)>> {
+                Some(vec![(x + 1, y)])
+            }
+
+            fn calc(&self, _map: &MapView, _x: i64, _y: i64, heights: &[f32]) -> f32 {
+                heights[0]
+            }
+        }
+
+        let mut map = Builder::new(17, 0.3)
+            .init_by(InitBy::None)
+            .generator(Box::new(Cycle))
+            .build();
+
+        assert_eq!(map.gen(3, 3), None);
+        assert_eq!(map.get(5, 3), None);
+
+        // A row is generated, once one of its points is set.
+        map.set(16, 3, Some(0.5));
+        assert_eq!(map.gen(3, 3), Some(0.5));
+        assert!(map
+            .gen_area((0, 0), (16, 4))
+            .iter()
+            .all(|p| p.2.is_some() == (p.1 == 3)));
+    }
+}
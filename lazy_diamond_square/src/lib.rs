@@ -10,9 +10,25 @@
     unused_qualifications
 )]
 
+mod context;
+mod distribution;
+mod generator;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod octaves;
+mod sample;
+mod shared;
+mod storage;
+mod version;
+mod world;
+
+use std::collections::HashSet;
 use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
 
-use seahash::{hash, hash_seeded};
+use seahash::hash;
 use tinyrand::{Rand, Seeded, StdRand};
 use tinyrand_std::clock_seed::ClockSeed;
 
@@ -20,6 +36,26 @@ use tinyrand_std::clock_seed::ClockSeed;
 pub use image::ImageBuffer;
 #[cfg(feature = "simple_viewing")]
 use image::LumaA;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+pub use context::{Context, Step};
+pub use distribution::{Distribution, InverseCdf};
+use generator::SquareSquare;
+pub use generator::{DiamondSquare, Generator, MapView};
+#[cfg(feature = "mmap")]
+pub use mmap::MmapStorage;
+pub use octaves::Octaves;
+pub use sample::{Fixed, Sample};
+pub use shared::SharedHeightMap;
+pub use storage::{
+    CachedStorage, ChunkedStorage, DenseStorage, Encoding, NanStorage, QuantizedStorage, Storage,
+    CHUNK_SIZE,
+};
+pub use version::Version;
+pub use world::World;
+
+use storage::LockedStorage;
 
 const MIN_SIZE_SHIFT: u8 = 3;
 /// The constant with the value of the minimum map size. If you
@@ -30,11 +66,13 @@ const MIN_SIZE_SHIFT: u8 = 3;
 /// # Examples
 ///
 /// ```
+/// use lazy_diamond_square::MIN_SIZE;
+///
 /// assert_eq!(MIN_SIZE, 9)
 /// ```
-pub const MIN_SIZE: i32 = (1 << MIN_SIZE_SHIFT) + 1;
+pub const MIN_SIZE: i64 = (1 << MIN_SIZE_SHIFT) + 1;
 
-const MAX_SIZE_SHIFT: u8 = 29;
+const MAX_SIZE_SHIFT: u8 = 61;
 /// The constant with the value of the maximum map size. If
 /// when calling `HeightMap::new` you specify the `size`
 /// parameter greater than this constant, it will change to
@@ -43,42 +81,110 @@ const MAX_SIZE_SHIFT: u8 = 29;
 /// # Examples
 ///
 /// ```
-/// assert_eq!(MAX_SIZE, 536870913)
+/// use lazy_diamond_square::MAX_SIZE;
+///
+/// assert_eq!(MAX_SIZE, 2305843009213693953)
 /// ```
-pub const MAX_SIZE: i32 = (1 << MAX_SIZE_SHIFT) + 1;
-
-/// This structure is the main structure in this crate.
-pub struct HeightMap {
-    map: Vec<Option<f32>>,
-    size: i32,
+pub const MAX_SIZE: i64 = (1 << MAX_SIZE_SHIFT) + 1;
+
+/// The number of points of one pass, which are passed to the
+/// generator at once at initialization.
+const INIT_BATCH_LEN: usize = 1 << 16;
+
+/// The number of points of one pass, which are calculated in
+/// parallel at once.
+#[cfg(feature = "rayon")]
+const PASS_BATCH_LEN: usize = 1 << 14;
+
+/// The type of `gen_rand_fn_with_context`.
+type RandFn<H> = dyn Fn(&Context<H>, u64) -> u64 + Send + Sync;
+/// The type of `calc_roughness_fn_with_context`.
+type RoughnessFn<H> = dyn Fn(&Context<H>, f32) -> f32 + Send + Sync;
+/// The type of `change_calced_h_fn_with_context`.
+type ChangeFn<H> = dyn Fn(&Context<H>, H) -> H + Send + Sync;
+/// The type of `gen_rand_fn`.
+type CoordsRandFn = dyn Fn(i64, i64, u64) -> u64 + Send + Sync;
+/// The type of `calc_roughness_fn`.
+type CoordsRoughnessFn = dyn Fn(i64, i64, f32) -> f32 + Send + Sync;
+/// The type of `change_calced_h_fn`.
+type CoordsChangeFn<H> = dyn Fn(i64, i64, H) -> H + Send + Sync;
+/// The constructor of the dense storage of the `width` by
+/// `height` cells, set by `encoding`.
+type DenseFn<H> = fn(i64, i64) -> Box<dyn Storage<H>>;
+
+/// This structure is the main structure in this crate. `H` is
+/// the type of the height values (see `Sample`).
+///
+/// The map is `Send` and `Sync`, so it can be moved to or shared
+/// with other threads (see `SharedHeightMap`), and the closures
+/// set by `Builder` must be `Send` and `Sync` too.
+///
+/// A copy of the map made by `try_clone` has its own copy of the
+/// cells, but shares the callbacks with the original.
+pub struct HeightMap<H: Sample = f32> {
+    map: Box<dyn Storage<H>>,
+    size: i64,
+    width: i64,
+    height: i64,
     roughness: f32,
+    square_roughness: f32,
     seed: u64,
     use_clock_seed: bool,
-    gen_rand_fn: Box<dyn Fn(i32, i32, u64) -> u64>,
-    calc_roughness_fn: Box<dyn Fn(i32, i32, f32) -> f32>,
-    change_calced_h_fn: Box<dyn Fn(i32, i32, f32) -> f32>,
+    edge_mode: EdgeMode,
+    displacement: Displacement,
+    square_displacement: Displacement,
+    distribution: Distribution,
+    unbounded: bool,
+    bounds: Option<(H, H)>,
+    init_by: InitBy,
+    levels: LockedStorage<H>,
+    generator: Arc<dyn Generator<H>>,
+    version: Version,
+    gen_rand_fn: Arc<RandFn<H>>,
+    calc_roughness_fn: Arc<RoughnessFn<H>>,
+    change_calced_h_fn: Arc<ChangeFn<H>>,
 }
 
 impl HeightMap {
     /// Returns a new instance of the `HeightMap` structure.
-    pub fn new(size: i32, roughness: f32) -> Self {
+    pub fn new(size: i64, roughness: f32) -> Self {
         let mut map = HeightMap::default();
         map.set_size(size);
         map.set_roughness(roughness);
-        map.map = vec![None; (map.size() * map.size()) as usize];
+        map.map = Box::new(DenseStorage::new(map.size()));
         map.init(1, InitBy::DiamondSquare);
 
         map
     }
     /// Returns a new seeded instance of the `HeightMap` structure.
-    pub fn new_with_seed(size: i32, roughness: f32, seed: &str) -> HeightMap {
-        let mut map = HeightMap::new(size, roughness);
+    pub fn new_with_seed(size: i64, roughness: f32, seed: &str) -> HeightMap {
+        let mut map = HeightMap::default();
+        map.set_size(size);
+        map.set_roughness(roughness);
         map.set_seed(seed);
+        map.map = Box::new(DenseStorage::new(map.size()));
+        map.init(1, InitBy::DiamondSquare);
 
         map
     }
+
+    fn to_range(min: f32, max: f32, h: u16) -> f32 {
+        let old_range = (u16::MAX as i64 - u16::MIN as i64) as f32;
+        let new_range = max - min;
+
+        let mut result = (h as i64 - u16::MIN as i64) as f32;
+
+        result /= old_range;
+        result *= new_range;
+        result += min;
+
+        result
+    }
+}
+
+impl<H: Sample> HeightMap<H> {
     /// Getter for `size` field.
-    pub fn size(&self) -> i32 {
+    pub fn size(&self) -> i64 {
         self.size
     }
     /// Returns the maximum value of the coordinate. If a value
@@ -96,23 +202,102 @@ impl HeightMap {
     ///
     /// assert_eq!(map.max_coord(), map.size() - 1);
     /// ```
-    pub fn max_coord(&self) -> i32 {
+    pub fn max_coord(&self) -> i64 {
         self.size() - 1
     }
-    /// Getter for `roughness` field.
+    /// Getter for `width` field. The map is made of several root
+    /// squares of the side `size`, which share their edges, so
+    /// the width is `columns * max_coord() + 1`. For a map of
+    /// one square it is equal to `size`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lazy_diamond_square::Builder;
+    ///
+    /// let map = Builder::new(65, 0.15).squares(3, 1).build();
+    ///
+    /// assert_eq!((map.width(), map.height()), (193, 65));
+    /// ```
+    pub fn width(&self) -> i64 {
+        self.width
+    }
+    /// Getter for `height` field. See `width`.
+    pub fn height(&self) -> i64 {
+        self.height
+    }
+    /// Returns the maximum value of the `x` coordinate.
+    pub fn max_x(&self) -> i64 {
+        self.width() - 1
+    }
+    /// Returns the maximum value of the `y` coordinate.
+    pub fn max_y(&self) -> i64 {
+        self.height() - 1
+    }
+    /// Getter for `roughness` field: the roughness of the
+    /// diamond step and of the square-square subdivision.
     pub fn roughness(&self) -> f32 {
         self.roughness
     }
+    /// Getter for `square_roughness` field: the roughness of the
+    /// square step.
+    pub fn square_roughness(&self) -> f32 {
+        self.square_roughness
+    }
     /// Getter for `seed` field.
     pub fn seed(&self) -> u64 {
         self.seed
     }
-    /// Getter for `use_clock_seed` field.
+    /// Getter for `use_clock_seed` field: `true` if the seed
+    /// has been drawn from the clock when the map was built.
     pub fn use_clock_seed(&self) -> bool {
         self.use_clock_seed
     }
+    /// Getter for `edge_mode` field.
+    pub fn edge_mode(&self) -> EdgeMode {
+        self.edge_mode
+    }
+    /// Getter for `displacement` field: the displacement of the
+    /// diamond step and of the square-square subdivision.
+    pub fn displacement(&self) -> Displacement {
+        self.displacement
+    }
+    /// Getter for `square_displacement` field: the displacement
+    /// of the square step.
+    pub fn square_displacement(&self) -> Displacement {
+        self.square_displacement
+    }
+    /// Getter for `distribution` field.
+    pub fn distribution(&self) -> &Distribution {
+        &self.distribution
+    }
+    /// Getter for `unbounded` field.
+    pub fn unbounded(&self) -> bool {
+        self.unbounded
+    }
+    /// Returns the lowest and the highest heights ever written to
+    /// the map: generated, set at initialization or by `set`, or
+    /// `None` if no height has been written yet. The bounds never
+    /// shrink, even if a height is overwritten or dropped by the
+    /// storage.
+    pub fn bounds(&self) -> Option<(H, H)> {
+        self.bounds
+    }
+    /// Getter for `init_by` field.
+    pub fn init_by(&self) -> InitBy {
+        self.init_by
+    }
+    /// Getter for `generator` field: the generator set by
+    /// `Builder::generator`, or the one selected by `InitBy`.
+    pub fn generator(&self) -> &dyn Generator<H> {
+        &*self.generator
+    }
+    /// Getter for `version` field.
+    pub fn version(&self) -> Version {
+        self.version
+    }
 
-    fn set_size(&mut self, size: i32) {
+    fn set_size(&mut self, size: i64) {
         if (MIN_SIZE..=MAX_SIZE).contains(&size) {
             let mut last_val = 0;
             let mut val;
@@ -139,6 +324,9 @@ impl HeightMap {
         } else {
             self.size = MAX_SIZE;
         }
+
+        self.width = self.size;
+        self.height = self.size;
     }
 
     fn set_seed(&mut self, seed: &str) {
@@ -151,10 +339,11 @@ impl HeightMap {
         } else {
             roughness.abs()
         };
+        self.square_roughness = self.roughness;
     }
     /// Returns the height value at specified coordinates. If
-    /// this value exceeds the range `0..=self.max_coord()`, it is
-    /// changed to valid coordinates.
+    /// this value exceeds the range `0..=self.max_x()` or
+    /// `0..=self.max_y()`, it is changed to valid coordinates.
     ///
     /// # Examples
     ///
@@ -168,15 +357,15 @@ impl HeightMap {
     /// assert_eq!(map.get(1, 0), None);
     /// assert_ne!(map.get(map.max_coord() + 1, 0), None);
     /// ```
-    pub fn get(&self, x: i32, y: i32) -> Option<f32> {
+    pub fn get(&self, x: i64, y: i64) -> Option<H> {
         let (x, y) = self.to_valid_coords(x, y);
 
-        self.map[(y * self.size() + x) as usize]
+        self.map.get(x, y)
     }
     /// Sets the passed value at specified coordinates and
     /// returns the value that was there before. If this coordinates
-    /// leaves the range `0..=self.max_coord()`, this coordinates are
-    /// changed to valid coordinates.
+    /// leaves the range `0..=self.max_x()` or `0..=self.max_y()`,
+    /// this coordinates are changed to valid coordinates.
     ///
     /// # Examples
     ///
@@ -192,15 +381,14 @@ impl HeightMap {
     /// assert_eq!(map.get(0, 0), Some(0.5));
     /// assert_eq!(map.get(1, 0), Some(0.5));
     /// ```
-    pub fn set(&mut self, x: i32, y: i32, h: Option<f32>) -> Option<f32> {
+    pub fn set(&mut self, x: i64, y: i64, h: Option<H>) -> Option<H> {
         let (x, y) = self.to_valid_coords(x, y);
 
-        let old = self.get(x, y);
-
-        let index = (y * self.size() + x) as usize;
-        self.map[index] = h;
+        if let Some(h) = h {
+            widen(&mut self.bounds, h);
+        }
 
-        old
+        self.map.set(x, y, h)
     }
     /// Checks the value against the specified coordinates, and if
     /// the value is `None`, then generates the new height value,
@@ -208,8 +396,9 @@ impl HeightMap {
     /// returns the resulting height value and `false`. Also
     /// returns `(None, false)` if the value cannot be generated
     /// because at least one corner of the map is set to `None`. If
-    /// this coordinate value is outside the range `0..=self.max_coord()`,
-    /// this coordinates are changed to valid coordinates.
+    /// this coordinate value is outside the range `0..=self.max_x()`
+    /// or `0..=self.max_y()`, this coordinates are changed to valid
+    /// coordinates.
     ///
     /// # Examples
     ///
@@ -229,115 +418,60 @@ impl HeightMap {
     /// assert_eq!(map.get(1, 0), Some(0.5));
     /// assert_ne!(map.get(2, 0), None);
     /// ```
-    pub fn gen(&mut self, x: i32, y: i32) -> Option<f32> {
-        let mut h = None;
-
-        let mut coords: Vec<[i32; 2]> = vec![[x, y]];
-
-        let mut last_coords = *coords.last().unwrap();
-
-        let max_coord = self.max_coord();
-
-        if (last_coords[0] == max_coord || last_coords[0] == 0)
-            && (last_coords[1] == max_coord || last_coords[1] == 0)
-        {
-            h = self.get(x, y)
-        } else if let Some(height) = self.get(x, y) {
-            h = Some(height);
-        } else {
-            let mut indexes: Vec<u8> = vec![0];
-            let mut heights: Vec<[f32; 4]> = vec![[0.0; 4]];
-
-            let mut last_index = *indexes.last().unwrap();
-            let mut last_heights = *heights.last().unwrap();
-            let mut len = coords.len();
-            let mut step = self.calc_step(x, y);
-
-            let mut diamond_step: bool = false;
-
-            if ((x & step) != 0) && ((y & step) != 0) {
-                diamond_step = true;
-            }
-
-            while len != 0 {
-                let (x, y) = self.calc_coords(
-                    last_coords[0],
-                    last_coords[1],
-                    step,
-                    last_index,
-                    diamond_step,
-                );
-                let (x, y) = self.to_valid_coords(x, y);
-
-                if let Some(height) = self.get(x, y) {
-                    last_heights[last_index as usize] = height;
-                    heights.pop();
-                    heights.push(last_heights);
-
-                    if last_index != 3 {
-                        indexes.pop();
-                        indexes.push(last_index + 1);
-                    } else {
-                        indexes.pop();
-                        coords.pop();
-                        heights.pop();
-
-                        if len == 1 {
-                            let height = Some(self.calc_h(x, y, last_heights));
-
-                            self.set(last_coords[0], last_coords[1], height);
-
-                            h = height;
-                        } else {
-                            self.set(
-                                last_coords[0],
-                                last_coords[1],
-                                Some(self.calc_h(x, y, last_heights)),
-                            );
-                        }
-
-                        len = coords.len();
-
-                        if len != 0 {
-                            last_coords = *coords.last().unwrap();
-                        }
-
-                        diamond_step = !diamond_step;
-                    }
-                } else {
-                    indexes.push(0);
-                    coords.push([x, y]);
-                    heights.push([0.0; 4]);
-
-                    last_coords = *coords.last().unwrap();
-                    len = coords.len();
-
-                    step = self.calc_step(x, y);
-
-                    diamond_step = ((x & step) != 0) && ((y & step) != 0);
-                }
-
-                if len != 0 {
-                    last_index = *indexes.last().unwrap();
-                    last_heights = *heights.last().unwrap();
-                }
-            }
-        }
-
-        h
+    pub fn gen(&mut self, x: i64, y: i64) -> Option<H> {
+        // `gen_in` borrows the settings and the storage at once, so
+        // the storage is moved out for the time of the generation.
+        // It is put back even if a closure of the map panics.
+        let mut map = std::mem::replace(&mut self.map, Box::new(NoStorage));
+        let mut bounds = self.bounds;
+        let h = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.gen_in(&mut *map, &mut bounds, x, y)
+        }));
+        (self.map, self.bounds) = (map, bounds);
+
+        h.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+    /// Returns a copy of the map, which is generated and changed
+    /// independently from the original, with the same settings
+    /// and callbacks. Fails if the storage can not be copied (see
+    /// `Storage::try_clone`).
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            map: self.map.try_clone()?,
+            size: self.size,
+            width: self.width,
+            height: self.height,
+            roughness: self.roughness,
+            square_roughness: self.square_roughness,
+            seed: self.seed,
+            use_clock_seed: self.use_clock_seed,
+            edge_mode: self.edge_mode,
+            displacement: self.displacement,
+            square_displacement: self.square_displacement,
+            distribution: self.distribution.clone(),
+            unbounded: self.unbounded,
+            bounds: self.bounds,
+            init_by: self.init_by,
+            levels: self.levels.clone(),
+            generator: Arc::clone(&self.generator),
+            version: self.version,
+            gen_rand_fn: Arc::clone(&self.gen_rand_fn),
+            calc_roughness_fn: Arc::clone(&self.calc_roughness_fn),
+            change_calced_h_fn: Arc::clone(&self.change_calced_h_fn),
+        })
     }
     /// Returns a vector of the results of calls to the `get`
     /// method for each point on the specified area.
     pub fn get_area(
         &self,
-        top_left: (i32, i32),
-        bottom_right: (i32, i32),
-    ) -> Vec<(i32, i32, Option<f32>)> {
-        let mut area = Vec::with_capacity(self.size().pow(2) as usize);
+        top_left: (i64, i64),
+        bottom_right: (i64, i64),
+    ) -> Vec<(i64, i64, Option<H>)> {
         let (top_left, bottom_right) = (
             self.to_valid_coords(top_left.0, top_left.1),
             self.to_valid_coords(bottom_right.0, bottom_right.1),
         );
+        let mut area = Vec::with_capacity(Self::area_len(top_left, bottom_right));
 
         for y in top_left.1..bottom_right.1 {
             for x in top_left.0..bottom_right.0 {
@@ -351,15 +485,15 @@ impl HeightMap {
     /// method for each point on the specified area.
     pub fn set_area(
         &mut self,
-        h: f32,
-        top_left: (i32, i32),
-        bottom_right: (i32, i32),
-    ) -> Vec<(i32, i32, Option<f32>)> {
-        let mut old_area = Vec::with_capacity(self.size().pow(2) as usize);
+        h: H,
+        top_left: (i64, i64),
+        bottom_right: (i64, i64),
+    ) -> Vec<(i64, i64, Option<H>)> {
         let (top_left, bottom_right) = (
             self.to_valid_coords(top_left.0, top_left.1),
             self.to_valid_coords(bottom_right.0, bottom_right.1),
         );
+        let mut old_area = Vec::with_capacity(Self::area_len(top_left, bottom_right));
 
         for y in top_left.1..bottom_right.1 {
             for x in top_left.0..bottom_right.0 {
@@ -369,22 +503,112 @@ impl HeightMap {
 
         old_area
     }
+    /// Returns the lowest and the highest heights of the
+    /// specified area, or `None` if the area has no heights.
+    pub fn area_bounds(&self, top_left: (i64, i64), bottom_right: (i64, i64)) -> Option<(H, H)> {
+        let mut bounds = None;
+
+        for (_, _, h) in self.get_area(top_left, bottom_right) {
+            if let Some(h) = h {
+                widen(&mut bounds, h);
+            }
+        }
+
+        bounds
+    }
+    /// Returns the heights of the specified area, as `get_area`
+    /// does, mapped linearly from the range `from` onto the range
+    /// `to`. The map itself is not changed, because the heights
+    /// generated later depend on the original heights.
+    ///
+    /// Pass `self.bounds()` as `from` to rescale the heights
+    /// of the whole map the same way or `self.area_bounds(..)` to
+    /// stretch the area to the full range.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lazy_diamond_square::{Builder, Displacement};
+    ///
+    /// let mut map = Builder::new(65, 1.0)
+    ///     .displacement(Displacement::Classic { hurst: 0.5 })
+    ///     .unbounded(true)
+    ///     .seed("qwerty")
+    ///     .build();
+    /// map.gen_area((0, 0), (64, 64));
+    ///
+    /// let area = map.normalize_area((0, 0), (64, 64), map.bounds().unwrap(), (0.0, 1.0));
+    ///
+    /// assert!(area.iter().all(|&(_, _, h)| (0.0..=1.0).contains(&h.unwrap())));
+    /// ```
+    pub fn normalize_area(
+        &self,
+        top_left: (i64, i64),
+        bottom_right: (i64, i64),
+        from: (H, H),
+        to: (H, H),
+    ) -> Vec<(i64, i64, Option<H>)> {
+        let mut area = self.get_area(top_left, bottom_right);
+
+        for (_, _, h) in area.iter_mut() {
+            *h = h.map(|h| h.rescale(from, to));
+        }
+
+        area
+    }
     /// Returns a vector of the results of calls to the `gen`
     /// method for each point on the specified area.
+    ///
+    /// The area is generated in batches of `CHUNK_SIZE` by
+    /// `CHUNK_SIZE` points, which are passed to
+    /// `Generator::gen_points`. `DiamondSquare` generates the
+    /// missing points of a batch and their missing ancestors
+    /// level by level, from the biggest step to the smallest one,
+    /// as `init` does. The result is the same as of the calls to
+    /// `gen`, but the ancestors are not looked up again for each
+    /// point.
     pub fn gen_area(
         &mut self,
-        top_left: (i32, i32),
-        bottom_right: (i32, i32),
-    ) -> Vec<(i32, i32, Option<f32>)> {
-        let mut area = Vec::with_capacity(self.size().pow(2) as usize);
+        top_left: (i64, i64),
+        bottom_right: (i64, i64),
+    ) -> Vec<(i64, i64, Option<H>)> {
         let (top_left, bottom_right) = (
             self.to_valid_coords(top_left.0, top_left.1),
             self.to_valid_coords(bottom_right.0, bottom_right.1),
         );
 
-        for y in top_left.1..bottom_right.1 {
-            for x in top_left.0..bottom_right.0 {
-                area.push((x, y, self.gen(x, y)));
+        let width = (bottom_right.0 - top_left.0).max(0) as usize;
+        let mut area = vec![(0, 0, None); Self::area_len(top_left, bottom_right)];
+        let generator = Arc::clone(&self.generator);
+        let mut points = Vec::new();
+
+        // The batches are aligned to the tiles of the chunked
+        // storages, so a bounded cache keeps most of the parents
+        // of a batch. The heights of a batch are taken right
+        // after it is generated, before the cache drops them.
+        for y in (top_left.1 & !(CHUNK_SIZE - 1)..bottom_right.1).step_by(CHUNK_SIZE as usize) {
+            for x in (top_left.0 & !(CHUNK_SIZE - 1)..bottom_right.0).step_by(CHUNK_SIZE as usize) {
+                let batch_top_left = (x.max(top_left.0), y.max(top_left.1));
+                let batch_bottom_right = (
+                    (x + CHUNK_SIZE).min(bottom_right.0),
+                    (y + CHUNK_SIZE).min(bottom_right.1),
+                );
+
+                points.clear();
+                points.extend(
+                    (batch_top_left.1..batch_bottom_right.1).flat_map(|y| {
+                        (batch_top_left.0..batch_bottom_right.0).map(move |x| (x, y))
+                    }),
+                );
+                generator.gen_points(self, &points);
+
+                for y in batch_top_left.1..batch_bottom_right.1 {
+                    for x in batch_top_left.0..batch_bottom_right.0 {
+                        let index = (y - top_left.1) as usize * width + (x - top_left.0) as usize;
+
+                        area[index] = (x, y, self.gen(x, y));
+                    }
+                }
             }
         }
 
@@ -399,8 +623,8 @@ impl HeightMap {
     #[cfg(feature = "simple_viewing")]
     pub fn get_img(
         &self,
-        top_left: (i32, i32),
-        bottom_right: (i32, i32),
+        top_left: (i64, i64),
+        bottom_right: (i64, i64),
         file_name: Option<&str>,
     ) -> ImageBuffer<LumaA<u8>, Vec<u8>> {
         let (top_left, bottom_right) = (
@@ -408,23 +632,22 @@ impl HeightMap {
             self.to_valid_coords(bottom_right.0, bottom_right.1),
         );
 
-        let mut img: ImageBuffer<LumaA<u8>, Vec<u8>>;
-        {
-            let size = (bottom_right.0 - top_left.0) as u32;
-            img = ImageBuffer::new(size, size);
-        }
+        let mut img: ImageBuffer<LumaA<u8>, Vec<u8>> = ImageBuffer::new(
+            (bottom_right.0 - top_left.0).max(0) as u32,
+            (bottom_right.1 - top_left.1).max(0) as u32,
+        );
 
-        let mut cur: Option<f32>;
+        let mut cur: Option<H>;
 
         for y in top_left.1 as u32..bottom_right.1 as u32 {
             for x in top_left.0 as u32..bottom_right.0 as u32 {
-                cur = self.get(x as i32, y as i32);
+                cur = self.get(x as i64, y as i64);
 
                 if let Some(cur_h) = cur {
                     img.put_pixel(
                         x - top_left.0 as u32,
                         y - top_left.1 as u32,
-                        LumaA([(255.0 * cur_h) as u8, 255]),
+                        LumaA([(255.0 * cur_h.to_f32()) as u8, 255]),
                     )
                 }
             }
@@ -448,108 +671,58 @@ impl HeightMap {
         }
 
         let max_coord = self.max_coord();
-        let size = self.size();
+        let (width, height) = (self.width(), self.height());
         let seed = self.seed();
-
-        let a = seed & 0xFFFF;
-        let b = seed & 0xFFFF0000;
-        let c = seed & 0xFFFF00000000;
-        let d = seed & 0xFFFF000000000000;
+        let version = self.version();
 
         match init_by {
             InitBy::DiamondSquare => {
                 {
-                    let corners = [
-                        (0, 0),
-                        (max_coord, 0),
-                        (0, max_coord),
-                        (max_coord, max_coord),
-                    ];
+                    let corners = (0..height)
+                        .step_by(max_coord as usize)
+                        .flat_map(|y| (0..width).step_by(max_coord as usize).map(move |x| (x, y)));
                     let (mut x, mut y);
 
                     for corner in corners {
                         (x, y) = (corner.0, corner.1);
-                        self.set(
-                            x,
-                            y,
-                            Some(Self::to_range(
-                                0.0,
-                                1.0,
-                                hash_seeded(
-                                    (x.to_string() + "_" + &y.to_string()).as_bytes(),
-                                    a,
-                                    b,
-                                    c,
-                                    d,
-                                ) as u16,
-                            )),
-                        );
+                        self.set(x, y, Some(H::from_u16(version.corner(x, y, seed))));
                     }
                 }
 
+                let generator = Arc::clone(&self.generator);
+                let mut points = Vec::with_capacity(INIT_BATCH_LEN);
                 let mut step = max_coord;
-                let mut shift = step >> 1;
-
-                let mut x = 0;
-                let mut y = 0;
 
                 for _ in 0..lvl {
-                    y += shift;
-                    x += shift;
-
-                    while y < size {
-                        while x < size {
-                            let h = self.calc_h(
-                                x,
-                                y,
-                                [
-                                    self.get(x + shift, y - shift).unwrap(),
-                                    self.get(x + shift, y + shift).unwrap(),
-                                    self.get(x - shift, y + shift).unwrap(),
-                                    self.get(x - shift, y - shift).unwrap(),
-                                ],
-                            );
-
-                            self.set(x, y, Some(h));
-
-                            x += step;
-                        }
+                    let shift = step >> 1;
+
+                    // The centres of the squares of the side `step`
+                    // (the diamond step) are on the odd rows and
+                    // columns of the grid of the step `shift`, and
+                    // the middles of their edges (the square step)
+                    // are on the other odd rows or columns.
+                    for is_diamond in [true, false] {
+                        for y in (0..height).step_by(shift as usize) {
+                            let odd_row = y & shift != 0;
+
+                            if is_diamond && !odd_row {
+                                continue;
+                            }
 
-                        x = step >> 1;
-                        y += step;
-                    }
+                            let left = if odd_row && !is_diamond { 0 } else { shift };
+                            points.extend((left..width).step_by(step as usize).map(|x| (x, y)));
 
-                    x = 0;
-                    y = 0;
-
-                    step >>= 1;
-
-                    while y < size {
-                        while x < size {
-                            if self.get(x, y).is_none() {
-                                let h = self.calc_h(
-                                    x,
-                                    y,
-                                    [
-                                        self.get_for_square(x, y - shift).unwrap(),
-                                        self.get_for_square(x + shift, y).unwrap(),
-                                        self.get_for_square(x, y + shift).unwrap(),
-                                        self.get_for_square(x - shift, y).unwrap(),
-                                    ],
-                                );
-
-                                self.set(x, y, Some(h));
+                            if points.len() >= INIT_BATCH_LEN {
+                                generator.gen_points(self, &points);
+                                points.clear();
                             }
-
-                            x += step;
                         }
-                        x = 0;
-                        y += step;
-                    }
 
-                    y = 0;
+                        generator.gen_points(self, &points);
+                        points.clear();
+                    }
 
-                    shift >>= 1;
+                    step = shift;
                 }
             }
             InitBy::Seed => {
@@ -559,16 +732,12 @@ impl HeightMap {
                 let mut y = 0;
                 let mut num_of_point = 0;
 
-                while y < size {
-                    while x < size {
+                while y < height {
+                    while x < width {
                         self.set(
                             x,
                             y,
-                            Some(Self::to_range(
-                                0.0,
-                                1.0,
-                                hash_seeded(num_of_point.to_string().as_bytes(), a, b, c, d) as u16,
-                            )),
+                            Some(H::from_u16(version.seed_point(num_of_point, seed))),
                         );
 
                         x += step;
@@ -578,219 +747,732 @@ impl HeightMap {
                     y += step;
                 }
             }
+            InitBy::SquareSquare => {
+                let shift = max_coord.trailing_zeros() - lvl as u32;
+                let (columns, rows) = (self.max_x() >> shift, self.max_y() >> shift);
+
+                for (i, j) in Self::grid(0, 1, columns + 1, rows + 1) {
+                    if shift == 0 {
+                        // The last level is the map itself.
+                        self.gen(i, j);
+                    } else {
+                        self.square_square(lvl as u32, i, j);
+                    }
+                }
+            }
             InitBy::None => (),
         }
     }
 
-    fn to_valid_coords(&self, x: i32, y: i32) -> (i32, i32) {
-        if x >= self.size() || y >= self.size() || x < 0 || y < 0 {
-            let mut x = x;
-            let mut y = y;
+    /// Returns the distance between the points set by `init`.
+    fn init_step(max_coord: i64, lvl: u8, init_by: InitBy) -> i64 {
+        match init_by {
+            InitBy::DiamondSquare => max_coord >> lvl.min(max_coord.trailing_zeros() as u8),
+            InitBy::Seed if lvl > 1 => (max_coord >> (lvl - 1)).max(1),
+            InitBy::Seed => 1,
+            InitBy::SquareSquare if lvl as u32 >= max_coord.trailing_zeros() => 1,
+            InitBy::SquareSquare | InitBy::None => max_coord,
+        }
+    }
 
-            let size = self.size();
+    fn to_valid_coords(&self, x: i64, y: i64) -> (i64, i64) {
+        let (max_x, max_y) = (self.max_x(), self.max_y());
 
-            while x < 0 {
-                x += size;
+        match self.edge_mode() {
+            EdgeMode::Wrap => (),
+            EdgeMode::Clamp | EdgeMode::InBounds => {
+                return (x.clamp(0, max_x), y.clamp(0, max_y));
             }
+            EdgeMode::Mirror => {
+                let mirror = |c: i64, max: i64| {
+                    let c = c.rem_euclid(max << 1);
 
-            x %= size;
+                    if c > max {
+                        (max << 1) - c
+                    } else {
+                        c
+                    }
+                };
 
-            while y < 0 {
-                y += size;
+                return (mirror(x, max_x), mirror(y, max_y));
             }
+        }
 
-            y %= size;
-
-            (x, y)
+        if x > max_x || y > max_y || x < 0 || y < 0 {
+            (x.rem_euclid(self.width()), y.rem_euclid(self.height()))
         } else {
             (x, y)
         }
     }
 
-    fn to_range(min: f32, max: f32, h: u16) -> f32 {
-        let old_range = (u16::MAX as i32 - u16::MIN as i32) as f32;
-        let new_range = max - min;
-
-        let mut result = (h as i32 - u16::MIN as i32) as f32;
+    /// Generates the missing points of the diamond-square
+    /// algorithm among `points` (which must be in valid
+    /// coordinates and unique) and their missing ancestors.
+    ///
+    /// The missing ancestors are collected pass by pass (the
+    /// diamond or the square step of one level), from the last
+    /// pass to the first one, as the missing parents of the points
+    /// collected for the later passes, which are not among
+    /// `points`. Then the passes are generated in the order of
+    /// `init`, so the same points are generated as by the calls to
+    /// `gen`.
+    pub(crate) fn gen_passes(&mut self, points: &[(i64, i64)]) {
+        // The pass of the step `s` is `2 * log2(s)` for the square
+        // step and one more for the diamond step, so the parents
+        // of a point are always in a later pass.
+        let pass = |x: i64, y: i64| {
+            let step = self.calc_step(x, y);
+            let is_diamond = (x & step != 0) && (y & step != 0);
+
+            2 * step.trailing_zeros() as usize + is_diamond as usize
+        };
+        let is_missing = |x: i64, y: i64| !self.map.is_generated(x, y) && !self.is_corner(x, y);
 
-        result /= old_range;
-        result *= new_range;
-        result += min;
+        if points.is_empty() {
+            return;
+        }
 
-        result
-    }
+        // The points are marked on their bounding box, if they
+        // fill most of it, as the batches of `gen_area` do.
+        // Otherwise, such as at initialization, their parents are
+        // usually generated already, and a missing parent among
+        // them is just generated in its own pass.
+        let (xs, ys) = (points.iter().map(|p| p.0), points.iter().map(|p| p.1));
+        let (left, top) = (xs.clone().min().unwrap(), ys.clone().min().unwrap());
+        let (width, height) = (xs.max().unwrap() - left + 1, ys.max().unwrap() - top + 1);
+        let mut marked = Vec::new();
+
+        if width as u128 * height as u128 <= 4 * points.len() as u128 {
+            marked = vec![false; (width * height) as usize];
+
+            for &(x, y) in points {
+                marked[((y - top) * width + x - left) as usize] = true;
+            }
+        }
 
-    fn calc_h(&self, x: i32, y: i32, heights: [f32; 4]) -> f32 {
-        let h = {
-            let sum = heights[0] + heights[1] + heights[2] + heights[3];
-            sum / 4.0
+        let is_marked = |x: i64, y: i64| {
+            !marked.is_empty()
+                && (left..left + width).contains(&x)
+                && (top..top + height).contains(&y)
+                && marked[((y - top) * width + x - left) as usize]
         };
 
-        let rand = {
-            if self.use_clock_seed() {
-                StdRand::seed(ClockSeed.next_u64()).next_u16()
-            } else {
-                StdRand::seed((self.gen_rand_fn)(x, y, self.seed())).next_u16()
+        // The missing points and their missing ancestors, which
+        // are not among them.
+        let len = 2 * self.max_coord().trailing_zeros() as usize;
+        let (mut passes, mut outside) = (vec![Vec::new(); len], vec![Vec::new(); len]);
+
+        for &(x, y) in points {
+            if is_missing(x, y) {
+                passes[pass(x, y)].push((x, y));
             }
-        };
+        }
 
-        let rand = Self::to_range(0.0, 1.0, rand);
-        let r = (self.calc_roughness_fn)(x, y, self.roughness());
+        for i in 0..len {
+            let mut points = std::mem::take(&mut outside[i]);
+            points.sort_unstable_by_key(|&(x, y)| (y, x));
+            points.dedup();
 
-        (self.change_calced_h_fn)(x, y, (r * rand) + (1.0 - r) * h)
-    }
+            for &(x, y) in passes[i].iter().chain(&points) {
+                for (px, py) in (0..4).filter_map(|index| self.parent(x, y, index)) {
+                    if !is_marked(px, py) && is_missing(px, py) {
+                        outside[pass(px, py)].push((px, py));
+                    }
+                }
+            }
 
-    fn get_for_square(&self, x: i32, y: i32) -> Option<f32> {
-        let (x, y) = self.to_valid_coords_for_square(x, y);
+            outside[i] = points;
+        }
 
-        self.map[(y * self.size() + x) as usize]
+        for (points, outside) in passes.into_iter().zip(outside).rev() {
+            self.gen_pass(outside.into_iter().chain(points));
+        }
     }
 
-    fn calc_step(&self, x: i32, y: i32) -> i32 {
-        let mut step = 1;
+    /// Returns the points `(x, y)`, where `x` and `y` are
+    /// `offset` plus a multiple of `step`.
+    fn grid(offset: i64, step: i64, width: i64, height: i64) -> impl Iterator<Item = (i64, i64)> {
+        (offset..height)
+            .step_by(step as usize)
+            .flat_map(move |y| (offset..width).step_by(step as usize).map(move |x| (x, y)))
+    }
 
-        while ((x & step) == 0) && ((y & step) == 0) {
-            step <<= 1;
-        }
+    /// Generates the missing points of one pass (the diamond or
+    /// the square step of one level), whose parents are expected
+    /// to be generated already. The points of one pass do not
+    /// depend on each other, so with the `rayon` feature they are
+    /// calculated in parallel, with the same result.
+    fn gen_pass(&mut self, points: impl Iterator<Item = (i64, i64)>) {
+        #[cfg(feature = "rayon")]
+        {
+            let mut batch = Vec::with_capacity(PASS_BATCH_LEN);
 
-        step
-    }
+            for (x, y) in points {
+                if self.map.is_generated(x, y) {
+                    continue;
+                }
 
-    fn calc_coords(&self, x: i32, y: i32, step: i32, index: u8, diamond_step: bool) -> (i32, i32) {
-        let (mut x, mut y) = (x, y);
+                if let Some(heights) = self.gen_parents(x, y) {
+                    batch.push((x, y, self.fill_heights(heights)));
+                }
 
-        if let 0 = index {
-            if diamond_step {
-                (x, y) = (x + step, y - step)
-            } else {
-                (x, y) = (x, y - step)
-            }
-        } else if let 1 = index {
-            if diamond_step {
-                (x, y) = (x + step, y + step)
-            } else {
-                (x, y) = (x + step, y)
-            }
-        } else if let 2 = index {
-            if diamond_step {
-                (x, y) = (x - step, y + step)
-            } else {
-                (x, y) = (x, y + step)
+                if batch.len() == PASS_BATCH_LEN {
+                    self.calc_batch(&mut batch);
+                }
             }
-        } else if diamond_step {
-            (x, y) = (x - step, y - step)
-        } else {
-            (x, y) = (x - step, y)
+
+            self.calc_batch(&mut batch);
         }
 
-        if !diamond_step {
-            if x < 0 {
-                x -= 1;
-            } else if x >= self.size() {
-                x += 1;
+        #[cfg(not(feature = "rayon"))]
+        for (x, y) in points {
+            if self.map.is_generated(x, y) {
+                continue;
             }
 
-            if y < 0 {
-                y -= 1;
-            } else if y >= self.size() {
-                y += 1;
+            if let Some(heights) = self.gen_parents(x, y) {
+                let h = self.calc_h(x, y, self.fill_heights(heights));
+                self.set(x, y, Some(h));
             }
         }
-
-        (x, y)
     }
 
-    fn to_valid_coords_for_square(&self, x: i32, y: i32) -> (i32, i32) {
-        let (mut x, mut y) = (x, y);
-
-        let max_coord = self.max_coord();
+    /// Calculates the points of the batch in parallel, sets them
+    /// and clears the batch.
+    #[cfg(feature = "rayon")]
+    fn calc_batch(&mut self, batch: &mut Vec<(i64, i64, [H; 4])>) {
+        let heights: Vec<H> = {
+            let calc_h = self.calc_h_fn();
+
+            batch
+                .par_iter()
+                .map(|&(x, y, heights)| calc_h(x, y, heights))
+                .collect()
+        };
 
-        if x < 0 {
-            x -= 1;
-        } else if x > max_coord {
-            x += 1;
+        for (&(x, y, _), h) in batch.iter().zip(heights) {
+            self.set(x, y, Some(h));
         }
 
-        if y < 0 {
-            y -= 1;
-        } else if y > max_coord {
-            y += 1
+        batch.clear();
+    }
+
+    /// Returns the heights of the parents of the point, generating
+    /// the ones dropped by the storage or lying outside of the
+    /// region of the current pass, or `None` if one of the
+    /// ancestors is a missing corner.
+    fn gen_parents(&mut self, x: i64, y: i64) -> Option<[Option<H>; 4]> {
+        let mut heights = [None; 4];
+
+        for index in 0..4 {
+            if let Some((px, py)) = self.parent(x, y, index) {
+                heights[index as usize] = Some(match self.get(px, py) {
+                    Some(h) => h,
+                    None => self.gen(px, py)?,
+                });
+            }
         }
 
-        self.to_valid_coords(x, y)
+        Some(heights)
     }
-}
 
-impl Default for HeightMap {
-    fn default() -> Self {
-        Self {
-            map: vec![],
-            size: MIN_SIZE,
-            roughness: 0.0,
-            seed: StdRand::seed(ClockSeed.next_u64()).next_u64(),
-            use_clock_seed: false,
-            gen_rand_fn: Box::new(|x: i32, y: i32, seed: u64| {
-                seed ^ {
-                    let mut x = x as u64;
-                    let mut y = y as u64;
-
-                    let mut xm7: u64 = x % 7;
-                    let mut xm13: u64 = x % 13;
-                    let mut xm1301081: u64 = x % 1301081;
-                    let mut ym8461: u64 = y % 8461;
-                    let mut ym105467: u64 = y % 105467;
-                    let mut ym105943: u64 = y % 105943;
-
-                    for _ in 0..80 {
-                        y = x + seed;
-                        x += xm7 + xm13 + xm1301081 + ym8461 + ym105467 + ym105943;
-                        xm7 = x % 7;
-                        xm13 = x % 13;
-                        xm1301081 = x % 1301081;
-                        ym8461 = y % 8461;
-                        ym105467 = y % 105467;
-                        ym105943 = y % 105943;
-                    }
+    /// Generates the height value at specified coordinates in
+    /// `cells`, which are used instead of the storage of the map,
+    /// and widens `bounds` by the generated heights.
+    fn gen_in(
+        &self,
+        cells: &mut dyn Storage<H>,
+        bounds: &mut Option<(H, H)>,
+        x: i64,
+        y: i64,
+    ) -> Option<H> {
+        let (x, y) = self.to_valid_coords(x, y);
+
+        if let Some(h) = cells.get(x, y) {
+            return Some(h);
+        }
+
+        let generator = self.generator();
+        let parents = |cells: &dyn Storage<H>, x, y| {
+            let parents = generator.parents(&MapView::new(self, cells), x, y)?;
 
-                    ((xm7 + xm13 + xm1301081 + ym8461 + ym105467 + ym105943) as f64 / 1520972.0)
-                        .to_bits()
+            Some(
+                parents
+                    .into_iter()
+                    .map(|(x, y)| self.to_valid_coords(x, y))
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        // Each frame is a point to be generated, its parents and
+        // the heights of the parents already looked at. The heights
+        // are kept in the frame, so the point is generated even if
+        // the storage has dropped a parent in the meantime. The
+        // points of the frames are kept in `path` too, so a point,
+        // which depends on itself, is not generated.
+        let mut frames = vec![((x, y), parents(cells, x, y)?, Vec::with_capacity(4))];
+        let mut path = HashSet::from([(x, y)]);
+
+        while let Some(((x, y), parents_of, heights)) = frames.last_mut() {
+            if heights.len() == parents_of.len() {
+                let height = generator.calc(&MapView::new(self, cells), *x, *y, heights);
+                cells.set(*x, *y, Some(height));
+                // The children get the height as the storage keeps
+                // it (see `Encoding::Quantized`), so it does not
+                // matter, whether they read it from the storage.
+                let height = cells.get(*x, *y).unwrap_or(height);
+                widen(bounds, height);
+
+                path.remove(&(*x, *y));
+                frames.pop();
+
+                match frames.last_mut() {
+                    Some((_, _, heights)) => heights.push(height),
+                    None => return Some(height),
                 }
-            }),
-            calc_roughness_fn: Box::new(|_x: i32, _y: i32, r: f32| r),
-            change_calced_h_fn: Box::new(|_x: i32, _y: i32, h: f32| h),
+
+                continue;
+            }
+
+            let (px, py) = parents_of[heights.len()];
+
+            match cells.get(px, py) {
+                Some(height) => heights.push(height),
+                None if !path.insert((px, py)) => return None,
+                None => frames.push(((px, py), parents(cells, px, py)?, Vec::with_capacity(4))),
+            }
         }
-    }
-}
 
-impl fmt::Debug for HeightMap {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("HeightMap")
-            .field("map", &self.map)
-            .field("size", &self.size)
-            .field("seed", &self.seed)
-            .field("roughness", &self.roughness)
-            .field("use_clock_seed", &self.use_clock_seed)
-            .finish()
+        None
     }
-}
-/// For more flexible customization of `HeightMap` parameters.
-/// The names of the methods correspond to the names of the
-/// fields to be set.
-pub struct Builder {
-    size: i32,
-    seed: u64,
-    roughness: f32,
-    use_clock_seed: bool,
-    gen_rand_fn: Box<dyn Fn(i32, i32, u64) -> u64>,
-    calc_roughness_fn: Box<dyn Fn(i32, i32, f32) -> f32>,
-    change_calced_h_fn: Box<dyn Fn(i32, i32, f32) -> f32>,
 
-    init_lvl: u8,
-    init_by: InitBy,
-}
+    fn area_len(top_left: (i64, i64), bottom_right: (i64, i64)) -> usize {
+        let width = (bottom_right.0 - top_left.0).max(0) as usize;
+        let height = (bottom_right.1 - top_left.1).max(0) as usize;
 
-impl Builder {
-    pub fn new(size: i32, roughness: f32) -> Builder {
+        width * height
+    }
+
+    fn calc_h(&self, x: i64, y: i64, heights: [H; 4]) -> H {
+        self.calc_h_fn()(x, y, heights)
+    }
+
+    /// Returns `calc_h`, which borrows only the fields it uses,
+    /// so it can be shared between threads.
+    fn calc_h_fn(&self) -> impl Fn(i64, i64, [H; 4]) -> H + Sync + '_ {
+        let displace = self.displace_fn();
+        let seed = self.seed();
+        let top_level = self.max_coord().trailing_zeros();
+
+        move |x, y, heights| {
+            // The top level is 0, the level of the step 1 is the last one.
+            let level = top_level - 1 - (x | y).trailing_zeros();
+
+            let step = 1 << (x | y).trailing_zeros();
+            let context = Context {
+                x,
+                y,
+                index: (x, y),
+                level,
+                step,
+                kind: if (x & step != 0) && (y & step != 0) {
+                    Step::Diamond
+                } else {
+                    Step::Square
+                },
+                parents: heights,
+                mean: H::mean(&heights),
+            };
+
+            displace(&context, seed)
+        }
+    }
+
+    /// Returns the function, which displaces the mean of the
+    /// parents of the point by the random number drawn with
+    /// `seed`.
+    fn displace_fn(&self) -> impl Fn(&Context<H>, u64) -> H + Sync + '_ {
+        let roughness = [self.roughness(), self.square_roughness()];
+        let (gen_rand_fn, calc_roughness_fn, change_calced_h_fn) = (
+            &self.gen_rand_fn,
+            &self.calc_roughness_fn,
+            &self.change_calced_h_fn,
+        );
+
+        let displacement = [self.displacement(), self.square_displacement()];
+        let (distribution, unbounded) = (self.distribution(), self.unbounded());
+
+        move |context, seed| {
+            let (h, level) = (context.mean, context.level);
+            let step = (context.kind == Step::Square) as usize;
+            let mut rand = StdRand::seed(gen_rand_fn(context, seed));
+            let r = calc_roughness_fn(context, roughness[step]);
+
+            let h = match displacement[step] {
+                Displacement::Blend => {
+                    let rand = match distribution {
+                        Distribution::Uniform => H::from_u16(rand.next_u16()),
+                        _ => {
+                            let d = (distribution.sample(&mut rand) + 1.0) / 2.0;
+
+                            H::from_f32(if unbounded { d } else { d.clamp(0.0, 1.0) })
+                        }
+                    };
+
+                    H::blend(rand, h, r)
+                }
+                Displacement::Classic { hurst } => {
+                    let amplitude = r * (-hurst).exp2().powi(level as i32);
+
+                    let h = H::displace(h, amplitude * distribution.sample(&mut rand));
+
+                    if unbounded {
+                        h
+                    } else {
+                        h.clamp_unit()
+                    }
+                }
+            };
+
+            change_calced_h_fn(context, h)
+        }
+    }
+
+    /// Returns the height of the point `(i, j)` of the level
+    /// `level` of the square-square subdivision.
+    ///
+    /// The level `k` of the subdivision is a lattice of `2^k`
+    /// squares per root square along each axis, shifted from the
+    /// previous level by a quarter of its spacing, and the last
+    /// level is the map itself. Along an axis the point `i` lies
+    /// between the points `i >> 1` and `(i >> 1) + 1` of the
+    /// previous level, three times nearer to `(i + 1) >> 1`, so
+    /// its parents are weighted 9:3:3:1. The levels between the
+    /// corners and the map are kept in `levels`, one under
+    /// another, so each point is generated once, as the points of
+    /// the diamond-square algorithm are.
+    fn square_square(&self, level: u32, i: i64, j: i64) -> H {
+        let max_coord = self.max_coord();
+        let (seed, version) = (self.seed(), self.version());
+
+        if level == 0 {
+            return H::from_u16(version.corner(i * max_coord, j * max_coord, seed));
+        }
+
+        let top_level = max_coord.trailing_zeros();
+        let (columns, rows) = (self.max_x() / max_coord, self.max_y() / max_coord);
+        // The first row of the level in `levels`.
+        let row = rows * ((1 << level) - 2) + level as i64 - 1;
+
+        if level < top_level {
+            if let Some(h) = self.levels.get(i, row + j) {
+                return h;
+            }
+        }
+
+        // The parents of the point `i` of the level with the last
+        // index `max`: the nearer one and the farther one.
+        let parents = |i: i64, max: i64| {
+            let (near, far) = ((i + 1) >> 1, (i >> 1) + ((i & 1) ^ 1));
+
+            (
+                self.to_valid_index(near, max),
+                self.to_valid_index(far, max),
+            )
+        };
+        let (near_x, far_x) = parents(i, columns << (level - 1));
+        let (near_y, far_y) = parents(j, rows << (level - 1));
+        let up = |i: i64, j: i64| self.square_square(level - 1, i, j);
+        let (nn, nf, fn_, ff) = (
+            up(near_x, near_y),
+            up(near_x, far_y),
+            up(far_x, near_y),
+            up(far_x, far_y),
+        );
+        let step = max_coord >> level;
+        let context = Context {
+            x: i * step - step / 2,
+            y: j * step - step / 2,
+            index: (i, j),
+            level: level - 1,
+            step,
+            kind: Step::SquareSquare,
+            parents: [nn, nf, fn_, ff],
+            mean: H::mean(&[
+                nn, nn, nn, nn, nn, nn, nn, nn, nn, nf, nf, nf, fn_, fn_, fn_, ff,
+            ]),
+        };
+        let h = self.displace_fn()(&context, version.level_seed(seed, level));
+
+        if level < top_level {
+            self.levels.set(i, row + j, Some(h));
+        }
+
+        h
+    }
+
+    /// Changes the index of a point of a square-square level,
+    /// which is at most one point beyond the last index `max`, to
+    /// a valid index, as the edge mode says.
+    fn to_valid_index(&self, i: i64, max: i64) -> i64 {
+        if i <= max {
+            return i;
+        }
+
+        match self.edge_mode() {
+            EdgeMode::Wrap => i - max,
+            EdgeMode::Mirror => (max << 1) - i,
+            EdgeMode::Clamp | EdgeMode::InBounds => max,
+        }
+    }
+
+    /// Returns `true` if the point is a corner of a root square.
+    fn is_corner(&self, x: i64, y: i64) -> bool {
+        let max_coord = self.max_coord();
+
+        (x | y) & (max_coord - 1) == 0
+    }
+
+    fn calc_step(&self, x: i64, y: i64) -> i64 {
+        1 << (x | y).trailing_zeros()
+    }
+
+    fn calc_coords(x: i64, y: i64, step: i64, index: u8, diamond_step: bool) -> (i64, i64) {
+        let (mut x, mut y) = (x, y);
+
+        if let 0 = index {
+            if diamond_step {
+                (x, y) = (x + step, y - step)
+            } else {
+                (x, y) = (x, y - step)
+            }
+        } else if let 1 = index {
+            if diamond_step {
+                (x, y) = (x + step, y + step)
+            } else {
+                (x, y) = (x + step, y)
+            }
+        } else if let 2 = index {
+            if diamond_step {
+                (x, y) = (x - step, y + step)
+            } else {
+                (x, y) = (x, y + step)
+            }
+        } else if diamond_step {
+            (x, y) = (x - step, y - step)
+        } else {
+            (x, y) = (x - step, y)
+        }
+
+        (x, y)
+    }
+
+    /// Returns the coordinates of the parent with the index
+    /// `index` (from 0 to 3) of the specified point, or `None` if
+    /// this parent is outside the map and the edge mode does not
+    /// use it.
+    fn parent(&self, x: i64, y: i64, index: u8) -> Option<(i64, i64)> {
+        let step = self.calc_step(x, y);
+        let diamond_step = ((x & step) != 0) && ((y & step) != 0);
+        let (x, y) = Self::calc_coords(x, y, step, index, diamond_step);
+
+        if (0..=self.max_x()).contains(&x) && (0..=self.max_y()).contains(&y) {
+            Some((x, y))
+        } else {
+            match self.edge_mode() {
+                EdgeMode::Wrap => Some(self.to_valid_coords_for_square(x, y)),
+                EdgeMode::Mirror => Some(self.to_valid_coords(x, y)),
+                EdgeMode::Clamp | EdgeMode::InBounds => None,
+            }
+        }
+    }
+
+    /// Replaces the height of the parent, which is not used
+    /// (only one parent of a point on the edge may be outside the
+    /// map), as the edge mode says.
+    fn fill_heights(&self, heights: [Option<H>; 4]) -> [H; 4] {
+        let Some(i) = heights.iter().position(Option::is_none) else {
+            return heights.map(Option::unwrap);
+        };
+
+        let fill = if self.edge_mode() == EdgeMode::Clamp {
+            H::mean(&[heights[(i + 1) % 4].unwrap(), heights[(i + 3) % 4].unwrap()])
+        } else {
+            H::mean(&heights.iter().flatten().copied().collect::<Vec<_>>())
+        };
+
+        heights.map(|h| h.unwrap_or(fill))
+    }
+
+    fn to_valid_coords_for_square(&self, x: i64, y: i64) -> (i64, i64) {
+        let (mut x, mut y) = (x, y);
+
+        if x < 0 {
+            x -= 1;
+        } else if x > self.max_x() {
+            x += 1;
+        }
+
+        if y < 0 {
+            y -= 1;
+        } else if y > self.max_y() {
+            y += 1
+        }
+
+        self.to_valid_coords(x, y)
+    }
+}
+
+/// Widens the lowest and the highest heights by `h`.
+fn widen<H: Sample>(bounds: &mut Option<(H, H)>, h: H) {
+    *bounds = match *bounds {
+        Some((min, max)) if h < min => Some((h, max)),
+        Some((min, max)) if h > max => Some((min, h)),
+        Some(bounds) => Some(bounds),
+        None => Some((h, h)),
+    };
+}
+
+/// The storage, which is put in place of the storage of a map
+/// while it is moved out.
+#[derive(Debug, Clone)]
+struct NoStorage;
+
+impl<H: Sample> Storage<H> for NoStorage {
+    fn get(&self, _x: i64, _y: i64) -> Option<H> {
+        None
+    }
+
+    fn set(&mut self, _x: i64, _y: i64, _h: Option<H>) -> Option<H> {
+        None
+    }
+}
+
+impl<H: Sample> Default for HeightMap<H> {
+    fn default() -> Self {
+        Self {
+            map: Box::<DenseStorage<H>>::default(),
+            size: MIN_SIZE,
+            width: MIN_SIZE,
+            height: MIN_SIZE,
+            roughness: 0.0,
+            square_roughness: 0.0,
+            seed: StdRand::seed(ClockSeed.next_u64()).next_u64(),
+            use_clock_seed: false,
+            edge_mode: EdgeMode::Wrap,
+            displacement: Displacement::Blend,
+            square_displacement: Displacement::Blend,
+            distribution: Distribution::Uniform,
+            unbounded: false,
+            bounds: None,
+            init_by: InitBy::DiamondSquare,
+            levels: LockedStorage::new(),
+            generator: Arc::new(DiamondSquare),
+            version: Version::V1,
+            gen_rand_fn: Version::V1.rand_fn(),
+            calc_roughness_fn: Arc::new(|_: &Context<H>, r: f32| r),
+            change_calced_h_fn: Arc::new(|_: &Context<H>, h: H| h),
+        }
+    }
+}
+
+impl<H: Sample> fmt::Debug for HeightMap<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HeightMap")
+            .field("map", &self.map)
+            .field("size", &self.size)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("seed", &self.seed)
+            .field("roughness", &self.roughness)
+            .field("square_roughness", &self.square_roughness)
+            .field("use_clock_seed", &self.use_clock_seed)
+            .field("edge_mode", &self.edge_mode)
+            .field("displacement", &self.displacement)
+            .field("square_displacement", &self.square_displacement)
+            .field("distribution", &self.distribution)
+            .field("unbounded", &self.unbounded)
+            .field("init_by", &self.init_by)
+            .field("generator", &self.generator)
+            .field("version", &self.version)
+            .finish()
+    }
+}
+/// For more flexible customization of `HeightMap` parameters.
+/// The names of the methods correspond to the names of the
+/// fields to be set. A configured builder can be cloned to build
+/// several maps, unless it has a storage set by `storage`: such a
+/// builder panics when it is cloned, and it is copied by
+/// `try_clone`.
+pub struct Builder<H: Sample = f32> {
+    size: i64,
+    columns: i64,
+    rows: i64,
+    seed: u64,
+    roughness: f32,
+    square_roughness: Option<f32>,
+    use_clock_seed: bool,
+    edge_mode: EdgeMode,
+    displacement: Displacement,
+    square_displacement: Option<Displacement>,
+    distribution: Distribution,
+    unbounded: bool,
+    chunked: bool,
+    encoding: Encoding,
+    dense_storage: DenseFn<H>,
+    max_chunks: Option<usize>,
+    storage: Option<Box<dyn Storage<H>>>,
+    generator: Option<Arc<dyn Generator<H>>>,
+    version: Version,
+    gen_rand_fn: Option<Arc<RandFn<H>>>,
+    calc_roughness_fn: Arc<RoughnessFn<H>>,
+    change_calced_h_fn: Arc<ChangeFn<H>>,
+
+    init_lvl: u8,
+    init_by: InitBy,
+}
+
+impl Builder {
+    pub fn new(size: i64, roughness: f32) -> Builder {
+        Builder::with_sample(size, roughness)
+    }
+    /// Sets the encoding of the cells of the dense storage.
+    /// Heights are still returned as `Option<f32>`, but
+    /// `Encoding::Quantized` rounds them and clamps them to the
+    /// range `0.0..=1.0`. No effect if `chunked` is `true`. The
+    /// maps with other sample types always use `DenseStorage`,
+    /// so there is no such method for them.
+    pub fn encoding(self, encoding: Encoding) -> Self {
+        let dense_storage: DenseFn<f32> = match encoding {
+            Encoding::Optional => |width, height| Box::new(DenseStorage::rect(width, height)),
+            Encoding::Nan => |width, height| Box::new(NanStorage::rect(width, height)),
+            Encoding::Quantized => {
+                |width, height| Box::new(QuantizedStorage::rect(width, height, 0.0, 1.0))
+            }
+        };
+
+        Self {
+            encoding,
+            dense_storage,
+            ..self
+        }
+    }
+}
+
+impl<H: Sample> Builder<H> {
+    /// The same as `new`, but for a map with the height values
+    /// of the type `H`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lazy_diamond_square::Builder;
+    ///
+    /// let mut map = Builder::<u16>::with_sample(65, 0.15).build();
+    ///
+    /// assert!(map.gen(10, 10).is_some());
+    /// ```
+    pub fn with_sample(size: i64, roughness: f32) -> Self {
         let mut self_size = size;
         let mut self_roughness = roughness.abs();
         if self_roughness > 1.0 {
@@ -830,37 +1512,233 @@ impl Builder {
             ..Default::default()
         }
     }
+    /// Makes the map of `columns` by `rows` root squares of the
+    /// side `size`, which share their edges, so that it is
+    /// `columns * (size - 1) + 1` points wide and
+    /// `rows * (size - 1) + 1` points high. The values are
+    /// changed to be at least 1 and to keep the width and the
+    /// height not greater than `MAX_SIZE`.
+    pub fn squares(self, columns: i64, rows: i64) -> Self {
+        let max = ((MAX_SIZE - 1) / (self.size - 1)).max(1);
+
+        Self {
+            columns: columns.clamp(1, max),
+            rows: rows.clamp(1, max),
+            ..self
+        }
+    }
     pub fn seed(self, seed: &str) -> Self {
         Self {
             seed: hash(seed.as_bytes()),
             ..self
         }
     }
+    /// Sets the seed to the value returned by `HeightMap::seed`,
+    /// so a map can be recreated from it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lazy_diamond_square::Builder;
+    ///
+    /// let mut map = Builder::new(65, 0.15).use_clock_seed(true).build();
+    /// let mut copy = Builder::new(65, 0.15).seed_value(map.seed()).build();
+    ///
+    /// assert_eq!(map.gen(10, 10), copy.gen(10, 10));
+    /// ```
+    pub fn seed_value(self, seed: u64) -> Self {
+        Self { seed, ..self }
+    }
+    /// If `by_clock` is `true`, a new seed (the seed of the
+    /// session) is drawn from the clock, when the map is built,
+    /// instead of the seed set by `seed` or `seed_value`. All the
+    /// heights depend only on this seed, which is returned by
+    /// `HeightMap::seed`, so each build gives a new map, but any
+    /// of them can be recreated later.
     pub fn use_clock_seed(self, by_clock: bool) -> Self {
         Self {
             use_clock_seed: by_clock,
             ..self
         }
     }
+    /// Sets what happens at the borders of the map. See
+    /// `EdgeMode`.
+    pub fn edge_mode(self, edge_mode: EdgeMode) -> Self {
+        Self { edge_mode, ..self }
+    }
+    /// Sets how the random displacement of a new point is
+    /// calculated from the roughness. See `Displacement`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lazy_diamond_square::{Builder, Displacement};
+    ///
+    /// let mut map = Builder::new(65, 0.5)
+    ///     .displacement(Displacement::Classic { hurst: 0.8 })
+    ///     .seed("qwerty")
+    ///     .build();
+    ///
+    /// assert!((0.0..=1.0).contains(&map.gen(10, 10).unwrap()));
+    /// ```
+    pub fn displacement(self, displacement: Displacement) -> Self {
+        Self {
+            displacement,
+            ..self
+        }
+    }
+    /// Sets the roughness of the square step, which is changed
+    /// in the same way as in `new`. By default, both steps have
+    /// the roughness passed to `new`. Tuning the steps separately
+    /// reduces the directional artefacts of the algorithm.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lazy_diamond_square::{Builder, Displacement};
+    ///
+    /// let mut map = Builder::new(65, 0.3)
+    ///     .square_roughness(0.2)
+    ///     .square_displacement(Displacement::Classic { hurst: 0.9 })
+    ///     .seed("qwerty")
+    ///     .build();
+    ///
+    /// assert_eq!(map.roughness(), 0.3);
+    /// assert_eq!(map.square_roughness(), 0.2);
+    /// assert!(map.gen(10, 10).is_some());
+    /// ```
+    pub fn square_roughness(self, roughness: f32) -> Self {
+        Self {
+            square_roughness: Some(roughness.abs().min(1.0)),
+            ..self
+        }
+    }
+    /// Sets the displacement of the square step. By default, it
+    /// is the one set by `displacement`.
+    pub fn square_displacement(self, displacement: Displacement) -> Self {
+        Self {
+            square_displacement: Some(displacement),
+            ..self
+        }
+    }
+    /// Sets the distribution of the random term of a new point.
+    /// See `Distribution`.
+    pub fn distribution(self, distribution: Distribution) -> Self {
+        Self {
+            distribution,
+            ..self
+        }
+    }
+    /// If `true`, the generated heights are not clamped to the
+    /// unit range of the sample type, so the displacements are
+    /// signed and unbounded (`u16` heights are still limited by
+    /// the type). It has an effect with `Displacement::Classic`
+    /// or a distribution other than `Distribution::Uniform`. To
+    /// get the full dynamic range back, see
+    /// `HeightMap::normalize_area`.
+    pub fn unbounded(self, unbounded: bool) -> Self {
+        Self { unbounded, ..self }
+    }
+    /// If `true`, the map is stored in tiles of `CHUNK_SIZE`
+    /// by `CHUNK_SIZE` cells, which are allocated only when a
+    /// value is first written into them. This allows to build
+    /// maps up to `MAX_SIZE` and pay only for the generated area.
+    pub fn chunked(self, chunked: bool) -> Self {
+        Self { chunked, ..self }
+    }
+    /// Limits the memory of the map to `max` tiles of
+    /// `CHUNK_SIZE` by `CHUNK_SIZE` cells (see `CachedStorage`).
+    /// The least recently used tiles are dropped and generated
+    /// again by `gen` when they are requested, so the values set
+    /// by hand may be lost. The points on the grid of
+    /// `CHUNK_SIZE` are kept in tiles of their own, so a dropped
+    /// tile is generated again from the nearest points. The
+    /// points set at initialization are never dropped and count
+    /// against the limit. The levels of `InitBy::SquareSquare`
+    /// kept aside from the storage are not limited. Overrides
+    /// `chunked` and `encoding`.
+    pub fn max_chunks(self, max: usize) -> Self {
+        Self {
+            max_chunks: Some(max),
+            ..self
+        }
+    }
+    /// Sets the storage of the cells of the map. The storage
+    /// must accept `x` in the range `0..width` and `y` in the
+    /// range `0..height` (`0..size` unless the map is
+    /// rectangular). Overrides `chunked`, `max_chunks` and
+    /// `encoding`.
+    pub fn storage(self, storage: Box<dyn Storage<H>>) -> Self {
+        Self {
+            storage: Some(storage),
+            ..self
+        }
+    }
+    /// Sets the version of the generation algorithm. The same
+    /// settings give the same map with the same version, so the
+    /// version should be set explicitly if the map is saved or
+    /// recreated from the seed later.
+    pub fn version(self, version: Version) -> Self {
+        Self { version, ..self }
+    }
+    /// Sets the generator of the missing heights instead of the
+    /// one selected by `init_by` (`DiamondSquare` by default). The
+    /// initialization still sets the corners or the seed points,
+    /// but the other points of the first levels are generated by
+    /// the generator. See `Generator`.
+    pub fn generator(self, generator: Box<dyn Generator<H>>) -> Self {
+        Self {
+            generator: Some(generator.into()),
+            ..self
+        }
+    }
     /// The closure set by this method are further used to
     /// generate random numbers during height generation
-    /// at a particular point. No effect if you set
-    /// `use_clock_seed` to `true`.
-    pub fn gen_rand_fn(self, f: Box<dyn Fn(i32, i32, u64) -> u64>) -> Self {
+    /// at a particular point. By default, it is the one of the
+    /// `version`. It gets the coordinates of the point and the
+    /// seed.
+    ///
+    /// With `InitBy::SquareSquare` the points of the levels
+    /// before the last one lie between the points of the map, and
+    /// the closure gets the coordinates of the nearest point of
+    /// the map to the top left, which may be outside the map by up
+    /// to half the step (see `Context::x`).
+    pub fn gen_rand_fn(self, f: Box<CoordsRandFn>) -> Self {
+        self.gen_rand_fn_with_context(Box::new(move |c, seed| f(c.x, c.y, seed)))
+    }
+    /// The same as `gen_rand_fn`, but the closure gets the point
+    /// as a `Context`.
+    pub fn gen_rand_fn_with_context(self, f: Box<RandFn<H>>) -> Self {
         Self {
-            gen_rand_fn: f,
+            gen_rand_fn: Some(f.into()),
             ..self
         }
     }
-    pub fn calc_roughness_fn(self, f: Box<dyn Fn(i32, i32, f32) -> f32>) -> Self {
+    /// Sets the closure, which returns the roughness of the point
+    /// from its coordinates and the roughness of the map. The
+    /// coordinates are the ones of `gen_rand_fn`.
+    pub fn calc_roughness_fn(self, f: Box<CoordsRoughnessFn>) -> Self {
+        self.calc_roughness_fn_with_context(Box::new(move |c, r| f(c.x, c.y, r)))
+    }
+    /// The same as `calc_roughness_fn`, but the closure gets the
+    /// point as a `Context`.
+    pub fn calc_roughness_fn_with_context(self, f: Box<RoughnessFn<H>>) -> Self {
         Self {
-            calc_roughness_fn: f,
+            calc_roughness_fn: f.into(),
             ..self
         }
     }
-    pub fn change_calced_h_fn(self, f: Box<dyn Fn(i32, i32, f32) -> f32>) -> Self {
+    /// Sets the closure, which changes the calculated height of
+    /// the point before it is set. The coordinates are the ones of
+    /// `gen_rand_fn`.
+    pub fn change_calced_h_fn(self, f: Box<CoordsChangeFn<H>>) -> Self {
+        self.change_calced_h_fn_with_context(Box::new(move |c, h| f(c.x, c.y, h)))
+    }
+    /// The same as `change_calced_h_fn`, but the closure gets the
+    /// point as a `Context`.
+    pub fn change_calced_h_fn_with_context(self, f: Box<ChangeFn<H>>) -> Self {
         Self {
-            change_calced_h_fn: f,
+            change_calced_h_fn: f.into(),
             ..self
         }
     }
@@ -876,14 +1754,45 @@ impl Builder {
             ..self
         }
     }
-    pub fn build<'a>(self) -> HeightMap {
+    pub fn build(self) -> HeightMap<H> {
+        let width = self.columns * (self.size - 1) + 1;
+        let height = self.rows * (self.size - 1) + 1;
+
         let mut map = HeightMap {
-            map: vec![None; (self.size * self.size) as usize],
+            map: match self.storage {
+                Some(storage) => storage,
+                None if self.max_chunks.is_some() => Box::new(CachedStorage::new(
+                    self.max_chunks.unwrap(),
+                    HeightMap::<H>::init_step(self.size - 1, self.init_lvl, self.init_by),
+                )),
+                None if self.chunked => Box::new(ChunkedStorage::new()),
+                None => (self.dense_storage)(width, height),
+            },
             size: self.size,
+            width,
+            height,
             roughness: self.roughness,
-            seed: self.seed,
+            square_roughness: self.square_roughness.unwrap_or(self.roughness),
+            seed: if self.use_clock_seed {
+                StdRand::seed(ClockSeed.next_u64()).next_u64()
+            } else {
+                self.seed
+            },
             use_clock_seed: self.use_clock_seed,
-            gen_rand_fn: self.gen_rand_fn,
+            edge_mode: self.edge_mode,
+            displacement: self.displacement,
+            square_displacement: self.square_displacement.unwrap_or(self.displacement),
+            distribution: self.distribution,
+            unbounded: self.unbounded,
+            bounds: None,
+            init_by: self.init_by,
+            levels: LockedStorage::new(),
+            generator: self.generator.unwrap_or_else(|| match self.init_by {
+                InitBy::SquareSquare => Arc::new(SquareSquare),
+                _ => Arc::new(DiamondSquare),
+            }),
+            version: self.version,
+            gen_rand_fn: self.gen_rand_fn.unwrap_or_else(|| self.version.rand_fn()),
             calc_roughness_fn: self.calc_roughness_fn,
             change_calced_h_fn: self.change_calced_h_fn,
         };
@@ -892,44 +1801,48 @@ impl Builder {
 
         map
     }
+    /// The same as `build`, but returns a map, which can be
+    /// generated by several threads at once. The storage settings
+    /// of the builder are ignored: the map is kept in tiles of
+    /// `CHUNK_SIZE` by `CHUNK_SIZE` cells, each behind its own
+    /// lock.
+    pub fn build_shared(self) -> SharedHeightMap<H> {
+        let storage = LockedStorage::new();
+        let map = Builder {
+            storage: Some(Box::new(storage.share())),
+            ..self
+        }
+        .build();
+
+        SharedHeightMap::new(map, storage)
+    }
 }
 
-impl Default for Builder {
+impl<H: Sample> Default for Builder<H> {
     fn default() -> Self {
         Self {
             size: MIN_SIZE,
+            columns: 1,
+            rows: 1,
             seed: StdRand::seed(ClockSeed.next_u64()).next_u64(),
             roughness: 0.0,
+            square_roughness: None,
             use_clock_seed: false,
-            gen_rand_fn: Box::new(|x: i32, y: i32, seed: u64| {
-                seed ^ {
-                    let mut x = x as u64;
-                    let mut y = y as u64;
-
-                    let mut xm7: u64 = x % 7;
-                    let mut xm13: u64 = x % 13;
-                    let mut xm1301081: u64 = x % 1301081;
-                    let mut ym8461: u64 = y % 8461;
-                    let mut ym105467: u64 = y % 105467;
-                    let mut ym105943: u64 = y % 105943;
-
-                    for _ in 0..80 {
-                        y = x + seed;
-                        x += xm7 + xm13 + xm1301081 + ym8461 + ym105467 + ym105943;
-                        xm7 = x % 7;
-                        xm13 = x % 13;
-                        xm1301081 = x % 1301081;
-                        ym8461 = y % 8461;
-                        ym105467 = y % 105467;
-                        ym105943 = y % 105943;
-                    }
-
-                    ((xm7 + xm13 + xm1301081 + ym8461 + ym105467 + ym105943) as f64 / 1520972.0)
-                        .to_bits()
-                }
-            }),
-            calc_roughness_fn: Box::new(|_x: i32, _y: i32, r: f32| r),
-            change_calced_h_fn: Box::new(|_x: i32, _y: i32, h: f32| h),
+            edge_mode: EdgeMode::Wrap,
+            displacement: Displacement::Blend,
+            square_displacement: None,
+            distribution: Distribution::Uniform,
+            unbounded: false,
+            chunked: false,
+            encoding: Encoding::Optional,
+            dense_storage: |width, height| Box::new(DenseStorage::rect(width, height)),
+            max_chunks: None,
+            storage: None,
+            generator: None,
+            version: Version::V1,
+            gen_rand_fn: None,
+            calc_roughness_fn: Arc::new(|_: &Context<H>, r: f32| r),
+            change_calced_h_fn: Arc::new(|_: &Context<H>, h: H| h),
 
             init_lvl: 1,
             init_by: InitBy::DiamondSquare,
@@ -937,33 +1850,209 @@ impl Default for Builder {
     }
 }
 
-impl fmt::Debug for Builder {
+impl<H: Sample> Clone for Builder<H> {
+    /// # Panics
+    ///
+    /// If the builder has a storage set by `storage`.
+    fn clone(&self) -> Self {
+        assert!(
+            self.storage.is_none(),
+            "a builder with a storage can not be cloned, see `Builder::try_clone`"
+        );
+
+        self.with_storage(None)
+    }
+}
+
+impl<H: Sample> Builder<H> {
+    /// Returns a copy of the builder with a copy of the storage
+    /// set by `storage` (see `Storage::try_clone`), for example
+    /// a `MmapStorage` is copied into anonymous memory. Fails if
+    /// the storage can not be copied.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let storage = match &self.storage {
+            Some(storage) => Some(storage.try_clone()?),
+            None => None,
+        };
+
+        Ok(self.with_storage(storage))
+    }
+
+    /// Returns a copy of the builder with the storage `storage`.
+    fn with_storage(&self, storage: Option<Box<dyn Storage<H>>>) -> Self {
+        Self {
+            size: self.size,
+            columns: self.columns,
+            rows: self.rows,
+            seed: self.seed,
+            roughness: self.roughness,
+            square_roughness: self.square_roughness,
+            use_clock_seed: self.use_clock_seed,
+            edge_mode: self.edge_mode,
+            displacement: self.displacement,
+            square_displacement: self.square_displacement,
+            distribution: self.distribution.clone(),
+            unbounded: self.unbounded,
+            chunked: self.chunked,
+            encoding: self.encoding,
+            dense_storage: self.dense_storage,
+            max_chunks: self.max_chunks,
+            storage,
+            generator: self.generator.clone(),
+            version: self.version,
+            gen_rand_fn: self.gen_rand_fn.clone(),
+            calc_roughness_fn: Arc::clone(&self.calc_roughness_fn),
+            change_calced_h_fn: Arc::clone(&self.change_calced_h_fn),
+            init_lvl: self.init_lvl,
+            init_by: self.init_by,
+        }
+    }
+}
+
+impl<H: Sample> fmt::Debug for Builder<H> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Builder")
             .field("size", &self.size)
+            .field("columns", &self.columns)
+            .field("rows", &self.rows)
             .field("seed", &self.seed)
             .field("roughness", &self.roughness)
+            .field("square_roughness", &self.square_roughness)
             .field("use_clock_seed", &self.use_clock_seed)
+            .field("edge_mode", &self.edge_mode)
+            .field("displacement", &self.displacement)
+            .field("square_displacement", &self.square_displacement)
+            .field("distribution", &self.distribution)
+            .field("unbounded", &self.unbounded)
+            .field("chunked", &self.chunked)
+            .field("encoding", &self.encoding)
+            .field("max_chunks", &self.max_chunks)
+            .field("storage", &self.storage)
             .field("init_lvl", &self.init_lvl)
             .field("init_by", &self.init_by)
+            .field("generator", &self.generator)
+            .field("version", &self.version)
             .finish()
     }
 }
 
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+/// This is an enumeration with possible models of the random
+/// displacement of a new point.
+pub enum Displacement {
+    /// The height is `r * rand + (1 - r) * avg`, where `r` is
+    /// the roughness and `avg` is the mean of the parents. The
+    /// amplitude of the noise doesn't depend on the step.
+    #[default]
+    Blend,
+    /// The textbook diamond-square: the height is
+    /// `avg + r * p^level * rand`, where `rand` is drawn from the
+    /// `Distribution` of the map, `level` is 0 for the biggest step and
+    /// grows by one each time the step halves, and `p` is the
+    /// persistence `2^(-hurst)`. The result is clamped to the
+    /// unit range of the sample type, unless the map is
+    /// unbounded.
+    ///
+    /// `hurst` is the Hurst exponent, usually in the range
+    /// `0.0..=1.0`: the bigger it is, the smoother the map.
+    Classic { hurst: f32 },
+}
+
+impl Displacement {
+    /// Returns the `Classic` model with the persistence `p`,
+    /// that is the factor the amplitude is multiplied by at each
+    /// level.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lazy_diamond_square::Displacement;
+    ///
+    /// assert_eq!(
+    ///     Displacement::from_persistence(0.5),
+    ///     Displacement::Classic { hurst: 1.0 }
+    /// );
+    /// ```
+    pub fn from_persistence(p: f32) -> Self {
+        Displacement::Classic { hurst: -p.log2() }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 /// This is an enumeration with possible options for
 /// initializing a height map of an instance of the
 /// `HeightMap` structure.
+#[non_exhaustive]
 pub enum InitBy {
     DiamondSquare,
     Seed,
     None,
+    /// The map is generated by Miller's square-square
+    /// subdivision instead of the diamond-square algorithm, from
+    /// the same corners, which avoids the creases along the axes.
+    /// The levels of the subdivision up to `init_lvl` are
+    /// generated in advance. The levels before the last one lie
+    /// between the points of the map, so they are kept aside from
+    /// the storage, and the points of the map are the last level.
+    SquareSquare,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// This is an enumeration with possible options for the
+/// borders of the map. It sets both how the coordinates
+/// outside the map are changed to valid coordinates and which
+/// height the square step uses for the parent of a point on
+/// the edge, which is outside the map.
+pub enum EdgeMode {
+    /// The map is a torus: the coordinates wrap around and the
+    /// opposite border is used. The map is tileable.
+    Wrap,
+    /// The coordinates are clamped to the border, and the
+    /// height of the outer parent is the average of the two
+    /// parents on the border.
+    Clamp,
+    /// The coordinates and the outer parent are reflected from
+    /// the border.
+    Mirror,
+    /// The coordinates are clamped to the border, and only the
+    /// three parents inside the map are averaged.
+    InBounds,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Generates the points of `area` in the reverse order by
+    /// `gen` and asserts that they get the heights of `area`.
+    pub(crate) fn assert_gen_reversed<H: PartialEq + fmt::Debug>(
+        area: &[(i64, i64, Option<H>)],
+        mut gen: impl FnMut(i64, i64) -> Option<H>,
+    ) {
+        for (x, y, h) in area.iter().rev() {
+            assert_eq!(&gen(*x, *y), h, "({x}, {y})");
+        }
+    }
+
+    /// Asserts that the map of `builder` generated lazily in the
+    /// reverse order is the same on the area between `top_left`
+    /// and `bottom_right` as the one generated by `init`, and
+    /// returns this area.
+    fn assert_lazy_matches_init(
+        builder: Builder,
+        top_left: (i64, i64),
+        bottom_right: (i64, i64),
+    ) -> Vec<(i64, i64, Option<f32>)> {
+        let levels = (builder.size - 1).trailing_zeros() as u8;
+        let mut full = builder.clone().init_lvl(levels).build();
+        let mut lazy = builder.init_lvl(1).build();
+        let area = full.gen_area(top_left, bottom_right);
+
+        assert_gen_reversed(&area, |x, y| lazy.gen(x, y));
+
+        area
+    }
+
     #[test]
     fn max_coord() {
         let map = HeightMap::new(MIN_SIZE, 0.15);
@@ -1004,4 +2093,649 @@ mod tests {
         assert_eq!(map.get(1, 0), Some(0.5));
         assert_ne!(map.get(2, 0), None);
     }
+
+    #[test]
+    fn new_with_seed() {
+        let mut a = HeightMap::new_with_seed(65, 0.15, "a");
+        let mut b = HeightMap::new_with_seed(65, 0.15, "a");
+
+        assert_eq!(a.get(0, 0), b.get(0, 0));
+        assert_eq!(a.gen_area((0, 0), (65, 65)), b.gen_area((0, 0), (65, 65)));
+        assert_eq!(
+            a.get_area((0, 0), (65, 65)),
+            Builder::new(65, 0.15)
+                .seed("a")
+                .init_lvl(6)
+                .build()
+                .get_area((0, 0), (65, 65))
+        );
+    }
+
+    #[test]
+    fn chunked() {
+        let mut dense = Builder::new(65, 0.15).seed("chunked").build();
+        let mut chunked = Builder::new(65, 0.15).seed("chunked").chunked(true).build();
+
+        assert_eq!(
+            dense.gen_area((0, 0), (64, 64)),
+            chunked.gen_area((0, 0), (64, 64))
+        );
+
+        let mut huge = Builder::new(MAX_SIZE, 0.15).chunked(true).build();
+        let max_coord = huge.max_coord();
+
+        assert_ne!(huge.get(max_coord, max_coord), None);
+        assert_eq!(huge.set(1000, 1000, Some(0.5)), None);
+        assert_eq!(huge.get(1000, 1000), Some(0.5));
+        assert_eq!(huge.get(1001, 1000), None);
+        assert_ne!(huge.gen(3 << 59, (1 << 60) + 7), None);
+    }
+
+    #[test]
+    fn encoding() {
+        let mut optional = Builder::new(65, 0.15).seed("encoding").build();
+        let mut nan = Builder::new(65, 0.15)
+            .seed("encoding")
+            .encoding(Encoding::Nan)
+            .build();
+        let mut quantized = Builder::new(65, 0.15)
+            .seed("encoding")
+            .encoding(Encoding::Quantized)
+            .build();
+
+        let area = optional.gen_area((0, 0), (64, 64));
+
+        assert_eq!(area, nan.gen_area((0, 0), (64, 64)));
+
+        // The rounded heights do not depend on the order, in which
+        // the points are generated.
+        let quantized_area = quantized.gen_area((0, 0), (64, 64));
+        let builder = Builder::new(65, 0.3)
+            .seed("q")
+            .encoding(Encoding::Quantized);
+        let init = builder.clone().init_lvl(6).build();
+        let (mut forward, mut reverse) = (builder.clone().build(), builder.build());
+
+        assert!(quantized_area.iter().all(|(_, _, h)| h.is_some()));
+
+        quantized.set(8, 8, Some(2.0));
+        optional.set(8, 8, Some(2.0));
+        assert_eq!(quantized.get(8, 8), Some(1.0));
+        assert_eq!(optional.get(8, 8), Some(2.0));
+
+        for (x, y) in HeightMap::<f32>::grid(0, 1, 65, 65) {
+            forward.gen(x, y);
+            reverse.gen(64 - x, 64 - y);
+        }
+
+        for (x, y) in HeightMap::<f32>::grid(0, 1, 65, 65) {
+            assert_eq!(forward.get(x, y), init.get(x, y));
+            assert_eq!(reverse.get(x, y), init.get(x, y));
+        }
+    }
+
+    #[test]
+    fn gen_order() {
+        assert_lazy_matches_init(Builder::new(65, 0.15).seed("gen_order"), (0, 0), (64, 64));
+    }
+
+    #[test]
+    fn max_chunks() {
+        let mut unbounded = Builder::new(257, 0.15).seed("max_chunks").build();
+        let mut bounded = Builder::new(257, 0.15)
+            .seed("max_chunks")
+            .init_lvl(2)
+            .init_by(InitBy::Seed)
+            .max_chunks(8)
+            .build();
+        let mut seeded = Builder::new(257, 0.15)
+            .seed("max_chunks")
+            .init_lvl(2)
+            .init_by(InitBy::Seed)
+            .build();
+
+        for y in (0..256).step_by(30) {
+            for x in (0..256).step_by(30) {
+                bounded.gen(x, y);
+            }
+        }
+
+        assert_eq!(
+            bounded.gen_area((0, 0), (256, 256)),
+            seeded.gen_area((0, 0), (256, 256))
+        );
+        assert_eq!(
+            unbounded.gen_area((0, 0), (256, 256)),
+            Builder::new(257, 0.15)
+                .seed("max_chunks")
+                .max_chunks(8)
+                .build()
+                .gen_area((0, 0), (256, 256))
+        );
+    }
+
+    #[test]
+    fn edge_mode() {
+        let modes = [
+            (EdgeMode::Wrap, false),
+            (EdgeMode::Clamp, true),
+            (EdgeMode::Mirror, true),
+            (EdgeMode::InBounds, true),
+        ];
+
+        for (mode, independent) in modes {
+            let builder = || {
+                Builder::new(17, 0.5)
+                    .seed("edge_mode")
+                    .init_lvl(3)
+                    .edge_mode(mode)
+            };
+            let (mut a, mut b) = (builder().build(), builder().build());
+
+            a.set(16, 2, Some(0.0));
+            b.set(16, 2, Some(1.0));
+
+            assert_eq!(a.gen(0, 1) == b.gen(0, 1), independent);
+
+            assert_lazy_matches_init(builder(), (0, 0), (16, 16));
+        }
+
+        let clamp = Builder::new(17, 0.5).edge_mode(EdgeMode::Clamp).build();
+        let mirror = Builder::new(17, 0.5).edge_mode(EdgeMode::Mirror).build();
+
+        assert_eq!(clamp.to_valid_coords(-3, 20), (0, 16));
+        assert_eq!(mirror.to_valid_coords(-3, 20), (3, 12));
+    }
+
+    #[test]
+    fn gen_area() {
+        let modes = [
+            EdgeMode::Wrap,
+            EdgeMode::Clamp,
+            EdgeMode::Mirror,
+            EdgeMode::InBounds,
+        ];
+
+        for mode in modes {
+            let builder = || {
+                Builder::new(33, 0.15)
+                    .seed("gen_area")
+                    .squares(2, 1)
+                    .edge_mode(mode)
+            };
+            let (mut batch, mut single) = (builder().build(), builder().build());
+
+            for map in [&mut batch, &mut single] {
+                map.set(20, 9, Some(0.5));
+                map.gen(47, 30);
+            }
+
+            let area = batch.gen_area((5, 3), (60, 31));
+
+            assert_gen_reversed(&area, |x, y| single.gen(x, y));
+            assert!(area.iter().all(|(_, _, h)| h.is_some()));
+
+            // Only the ancestors of the area are generated.
+            for y in 0..=batch.max_y() {
+                for x in 0..=batch.max_x() {
+                    assert_eq!(batch.get(x, y), single.get(x, y));
+                }
+            }
+        }
+
+        let builder = || Builder::new(257, 0.15).seed("gen_area");
+        let (mut batch, mut single) = (builder().build(), builder().build());
+
+        batch.gen_area((100, 100), (102, 102));
+
+        for (x, y) in [(100, 100), (101, 100), (100, 101), (101, 101)] {
+            single.gen(x, y);
+        }
+
+        for y in 0..=256 {
+            for x in 0..=256 {
+                assert_eq!(batch.get(x, y), single.get(x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn clone() {
+        let builder = Builder::new(65, 0.15)
+            .seed("clone")
+            .max_chunks(8)
+            .calc_roughness_fn(Box::new(|x, _y, r| if x < 32 { r } else { r / 2.0 }));
+        let mut map = builder.clone().build();
+        let mut fork = map.try_clone().unwrap();
+
+        fork.set(10, 10, Some(0.0));
+
+        assert_eq!(map.get(10, 10), None);
+        assert_eq!(fork.gen(11, 11), fork.get(11, 11));
+        assert_eq!(
+            map.gen_area((0, 0), (64, 64)),
+            builder.build().gen_area((0, 0), (64, 64))
+        );
+
+        #[derive(Debug)]
+        struct Uncopyable(DenseStorage);
+
+        impl Storage for Uncopyable {
+            fn get(&self, x: i64, y: i64) -> Option<f32> {
+                self.0.get(x, y)
+            }
+
+            fn set(&mut self, x: i64, y: i64, h: Option<f32>) -> Option<f32> {
+                self.0.set(x, y, h)
+            }
+        }
+
+        let builder = Builder::new(9, 0.15).storage(Box::new(Uncopyable(DenseStorage::new(9))));
+
+        assert!(builder.try_clone().is_err());
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| builder.clone())).is_err());
+        assert!(builder.build().try_clone().is_err());
+
+        let dense = Builder::new(9, 0.15).storage(Box::new(DenseStorage::new(9)));
+        dense.try_clone().unwrap().build().set(0, 0, Some(0.5));
+
+        assert!(dense.build().get(0, 0) != Some(0.5));
+    }
+
+    #[test]
+    fn use_clock_seed() {
+        let builder = Builder::new(65, 0.15).seed("ignored").use_clock_seed(true);
+        let map = builder.clone().init_lvl(6).build();
+        let mut lazy = builder.build();
+
+        assert!(map.use_clock_seed());
+        assert_ne!(map.seed(), hash(b"ignored"));
+
+        let copy = Builder::new(65, 0.15).seed_value(map.seed());
+
+        assert_eq!(
+            assert_lazy_matches_init(copy, (0, 0), (64, 64)),
+            map.get_area((0, 0), (64, 64))
+        );
+
+        // The lazily generated points use the seed of the session
+        // too, not the one of the builder.
+        let mut from_seed = Builder::new(65, 0.15).seed_value(lazy.seed()).build();
+
+        assert_ne!(lazy.seed(), hash(b"ignored"));
+        assert_eq!(lazy.gen(10, 10), from_seed.gen(10, 10));
+    }
+
+    #[test]
+    fn squares() {
+        let builder = Builder::new(17, 0.15).seed("squares").squares(3, 2);
+        let mut map = builder.clone().build();
+
+        assert_eq!((map.width(), map.height()), (49, 33));
+        assert_eq!(map.gen(49, -1), map.gen(0, 32));
+
+        let area = assert_lazy_matches_init(builder, (0, 0), (48, 32));
+
+        assert!(area.iter().all(|(_, _, h)| h.is_some()));
+    }
+
+    #[test]
+    fn sample_types() {
+        let mut float = Builder::new(33, 0.15).seed("sample_types").build();
+        let mut double = Builder::<f64>::with_sample(33, 0.15)
+            .seed("sample_types")
+            .build();
+        let mut integer = Builder::<u16>::with_sample(33, 0.15)
+            .seed("sample_types")
+            .chunked(true)
+            .build();
+        let mut fixed = Builder::<Fixed>::with_sample(33, 0.15)
+            .seed("sample_types")
+            .build();
+
+        for (x, y) in [(1, 1), (16, 16), (31, 7), (0, 5)] {
+            let f = float.gen(x, y).unwrap();
+
+            assert!((double.gen(x, y).unwrap() as f32 - f).abs() < 1e-3);
+            assert!((integer.gen(x, y).unwrap().to_f32() - f).abs() < 1e-3);
+            assert!((fixed.gen(x, y).unwrap().to_f32() - f).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn displacement() {
+        let mut map = Builder::<f64>::with_sample(65, 0.5)
+            .seed("displacement")
+            .displacement(Displacement::Classic { hurst: 1.0 })
+            .build();
+        map.gen_area((0, 0), (64, 64));
+
+        for i in 0..65 {
+            map.gen(64, i);
+            map.gen(i, 64);
+        }
+
+        let mut max_d = [0.0; 6];
+        for (x, y) in (0..65).flat_map(|y| (0..65).map(move |x| (x, y))) {
+            if map.is_corner(x, y) {
+                continue;
+            }
+
+            let heights = [0, 1, 2, 3].map(|i| {
+                let (x, y) = map.parent(x, y, i).unwrap();
+                map.get(x, y)
+            });
+            let h = f64::mean(&map.fill_heights(heights));
+            let level = 5 - map.calc_step(x, y).trailing_zeros() as usize;
+            let d = (map.get(x, y).unwrap() - h).abs();
+
+            assert!(d <= 0.5 * 0.5f64.powi(level as i32) + 1e-6);
+            max_d[level] = d.max(max_d[level]);
+        }
+
+        assert!(max_d[0] > 0.5 * 0.5f64.powi(5));
+        assert_eq!(
+            Displacement::from_persistence(0.5),
+            Displacement::Classic { hurst: 1.0 }
+        );
+    }
+
+    #[test]
+    fn unbounded() {
+        let builder = Builder::<f64>::with_sample(65, 1.0)
+            .seed("unbounded")
+            .displacement(Displacement::Classic { hurst: 0.0 });
+        let mut bounded = builder.clone().build();
+        let mut unbounded = builder.unbounded(true).build();
+
+        bounded.gen_area((0, 0), (64, 64));
+        unbounded.gen_area((0, 0), (64, 64));
+
+        let (min, max) = unbounded.bounds().unwrap();
+        assert!(min < 0.0 || max > 1.0);
+
+        let area_bounds = unbounded.area_bounds((0, 0), (64, 64)).unwrap();
+        assert!(min <= area_bounds.0 && max >= area_bounds.1);
+
+        let (min, max) = bounded.bounds().unwrap();
+        assert!(min >= 0.0 && max <= 1.0);
+
+        let area = unbounded.normalize_area((0, 0), (64, 64), area_bounds, (-1.0, 1.0));
+        let (min, max) = area
+            .iter()
+            .fold((f64::MAX, f64::MIN), |(min, max), &(_, _, h)| {
+                (min.min(h.unwrap()), max.max(h.unwrap()))
+            });
+        assert_eq!((min, max), (-1.0, 1.0));
+
+        let mut map = Builder::new(17, 0.5).seed("unbounded").build();
+        map.set(1, 1, Some(-2.0));
+        map.gen(3, 3);
+        assert_eq!(map.bounds().unwrap().0, -2.0);
+    }
+
+    #[test]
+    fn square_square() {
+        for mode in [
+            EdgeMode::Wrap,
+            EdgeMode::Mirror,
+            EdgeMode::Clamp,
+            EdgeMode::InBounds,
+        ] {
+            let builder = Builder::new(33, 0.3)
+                .seed("square_square")
+                .squares(2, 1)
+                .edge_mode(mode)
+                .init_by(InitBy::SquareSquare);
+            let mut batch = builder.clone().build();
+            let mut single = builder.build();
+
+            assert_eq!(batch.get(0, 0), None);
+
+            let area = batch.gen_area((0, 0), (64, 32));
+
+            assert!(area
+                .iter()
+                .all(|(_, _, h)| (0.0..=1.0).contains(&h.unwrap())));
+            assert_gen_reversed(&area, |x, y| single.gen(x, y));
+        }
+
+        // Without the noise the heights are the weighted means of
+        // the corners.
+        let mut smooth = Builder::new(17, 0.0)
+            .seed("square_square")
+            .init_by(InitBy::SquareSquare)
+            .build();
+        let version = smooth.version();
+        let corners = [(0, 0), (16, 0), (0, 16), (16, 16)]
+            .map(|(x, y)| f32::from_u16(version.corner(x, y, smooth.seed())));
+        let (min, max) = (
+            corners.iter().copied().fold(f32::MAX, f32::min),
+            corners.iter().copied().fold(f32::MIN, f32::max),
+        );
+
+        for (_, _, h) in smooth.gen_area((0, 0), (16, 16)) {
+            assert!((min - 1e-6..=max + 1e-6).contains(&h.unwrap()));
+        }
+    }
+
+    #[test]
+    fn square_square_levels() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let builder = Builder::new(33, 0.3)
+            .seed("square_square_levels")
+            .init_by(InitBy::SquareSquare);
+        let mut lazy = builder
+            .clone()
+            .change_calced_h_fn(Box::new(move |_, _, h| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                h
+            }))
+            .build();
+        let area: Vec<_> = HeightMap::<f32>::grid(0, 1, 33, 33)
+            .map(|(x, y)| lazy.gen(x, y))
+            .collect();
+
+        // Each point of the levels between the corners and the map
+        // is generated once.
+        let levels: usize = (1..5).map(|k| ((1 << k) + 1) * ((1 << k) + 1)).sum();
+        assert_eq!(calls.load(Ordering::Relaxed), levels + 33 * 33);
+
+        for lvl in [2, 5] {
+            let mut init = builder.clone().init_lvl(lvl).build();
+
+            assert_eq!(init.get(10, 10).is_some(), lvl == 5);
+            assert!(HeightMap::<f32>::grid(0, 1, 33, 33)
+                .zip(&area)
+                .all(|((x, y), &h)| init.gen(x, y) == h));
+        }
+
+        // The points of the map are the last level, so a height set
+        // by hand is kept, but the other points do not depend on it.
+        let mut set = builder.build();
+        set.set(10, 10, Some(0.5));
+        assert_eq!(set.gen(10, 10), Some(0.5));
+        assert_eq!(set.gen(11, 10), lazy.get(11, 10));
+    }
+
+    #[test]
+    fn context() {
+        use std::sync::Mutex;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let mut map = Builder::new(17, 0.3)
+            .seed("context")
+            .change_calced_h_fn_with_context(Box::new(move |c, h| {
+                log.lock().unwrap().push(*c);
+                h
+            }))
+            .build();
+
+        map.gen(1, 1);
+
+        let seen = seen.lock().unwrap();
+        let last = seen.last().unwrap();
+
+        assert_eq!((last.x, last.y, last.step, last.level), (1, 1, 1, 3));
+        assert_eq!(last.kind, Step::Diamond);
+        assert_eq!(last.mean, f32::mean(&last.parents));
+        assert!(seen.iter().any(|c| c.kind == Step::Square && c.step == 4));
+        assert!(seen.iter().all(|c| c.level == 3 - c.step.trailing_zeros()));
+
+        drop(seen);
+
+        let mut coarse = Builder::new(17, 0.3)
+            .seed("context")
+            .calc_roughness_fn_with_context(Box::new(|c, r| if c.level == 0 { r } else { 0.0 }))
+            .build();
+
+        // Without the noise a point of the finest level is the mean
+        // of its parents.
+        let heights = [(0, 0), (2, 0), (2, 2), (0, 2)].map(|(x, y)| coarse.gen(x, y).unwrap());
+        assert_eq!(coarse.gen(1, 1), Some(f32::mean(&heights)));
+
+        // The closures of the coordinates get the coordinates of
+        // the context.
+        let builder = Builder::new(33, 0.3).seed("context");
+        let mut by_coords = builder
+            .clone()
+            .calc_roughness_fn(Box::new(|x, _y, r| if x < 16 { r } else { 0.0 }))
+            .build();
+        let mut by_context = builder
+            .calc_roughness_fn_with_context(Box::new(|c, r| if c.x < 16 { r } else { 0.0 }))
+            .build();
+        assert_eq!(
+            by_coords.gen_area((0, 0), (32, 32)),
+            by_context.gen_area((0, 0), (32, 32))
+        );
+
+        // The points of the square-square levels get the
+        // coordinates of the map near them.
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let mut square_square = Builder::new(33, 0.3)
+            .seed("context")
+            .init_by(InitBy::SquareSquare)
+            .change_calced_h_fn_with_context(Box::new(move |c, h| {
+                log.lock().unwrap().push(*c);
+                h
+            }))
+            .build();
+
+        seen.lock().unwrap().clear();
+        square_square.gen(20, 5);
+
+        let seen = seen.lock().unwrap();
+
+        assert!(seen.iter().all(|c| {
+            let (position_x, position_y) = (
+                c.index.0 as f32 * c.step as f32 - (c.step - 1) as f32 / 2.0,
+                c.index.1 as f32 * c.step as f32 - (c.step - 1) as f32 / 2.0,
+            );
+
+            (c.x, c.y) == (position_x.floor() as i64, position_y.floor() as i64)
+                && (-c.step / 2..=32).contains(&c.x)
+                && (20 - 2 * c.step..=20 + 2 * c.step).contains(&c.x)
+                && (5 - 2 * c.step..=5 + 2 * c.step).contains(&c.y)
+        }));
+        assert_eq!(
+            seen.last().map(|c| (c.x, c.y, c.index)),
+            Some((20, 5, (20, 5)))
+        );
+    }
+
+    #[test]
+    fn square_roughness() {
+        use std::sync::Mutex;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let mut map = Builder::new(33, 0.4)
+            .seed("square_roughness")
+            .square_roughness(0.0)
+            .square_displacement(Displacement::Blend)
+            .displacement(Displacement::Classic { hurst: 0.5 })
+            .change_calced_h_fn_with_context(Box::new(move |c, h| {
+                log.lock().unwrap().push((c.kind, c.mean, h));
+                h
+            }))
+            .build();
+
+        map.gen_area((0, 0), (32, 32));
+
+        let seen = seen.lock().unwrap();
+
+        // Without the noise a point of the square step is the mean
+        // of its parents.
+        assert!(seen
+            .iter()
+            .filter(|&&(kind, ..)| kind == Step::Square)
+            .all(|&(_, mean, h)| h == mean));
+        assert!(seen
+            .iter()
+            .filter(|&&(kind, ..)| kind == Step::Diamond)
+            .any(|&(_, mean, h)| h != mean));
+        assert_eq!((map.roughness(), map.square_roughness()), (0.4, 0.0));
+        assert_eq!(map.square_displacement(), Displacement::Blend);
+        assert_eq!(
+            Builder::new(9, 0.4)
+                .square_roughness(-2.0)
+                .build()
+                .square_roughness(),
+            1.0
+        );
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_passes() {
+        let builder = || {
+            Builder::new(257, 0.3)
+                .seed("parallel_passes")
+                .displacement(Displacement::Classic { hurst: 0.5 })
+        };
+        // The last passes are longer than `PASS_BATCH_LEN`, so
+        // they are calculated in parallel.
+        let parallel = builder().init_lvl(8).build();
+        let mut serial = builder().init_lvl(0).build();
+
+        for y in (0..=256).rev() {
+            for x in (0..=256).rev() {
+                assert_eq!(
+                    serial.gen(x, y).map(f32::to_bits),
+                    parallel.get(x, y).map(f32::to_bits)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn gen_after_panic() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let fail = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&fail);
+        let builder = || Builder::new(33, 0.15).seed("gen_after_panic");
+        let mut map = builder()
+            .change_calced_h_fn(Box::new(move |_, _, h| {
+                assert!(!flag.load(Ordering::Relaxed));
+                h
+            }))
+            .build();
+        let mut copy = builder().build();
+
+        let h = map.gen(3, 5);
+        fail.store(true, Ordering::Relaxed);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| map.gen(20, 7)));
+        fail.store(false, Ordering::Relaxed);
+
+        assert!(result.is_err());
+        assert_eq!(map.get(3, 5), h);
+        assert_eq!(map.gen(20, 7), copy.gen(20, 7));
+    }
 }
@@ -0,0 +1,212 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use memmap2::MmapMut;
+
+use crate::Storage;
+
+const CELL_LEN: usize = 4;
+
+/// The storage, which keeps the cells of the map in a
+/// memory-mapped file, so the map may be larger than the
+/// memory and persists across runs.
+///
+/// Each cell takes 4 bytes and holds the bitwise negation of
+/// its `f32` value in little-endian order. A zeroed cell is
+/// decoded as NaN, which is the "not generated" sentinel, so a
+/// freshly created file does not need to be filled. Setting
+/// a NaN height is the same as setting `None`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use lazy_diamond_square::{Builder, InitBy, MmapStorage};
+///
+/// let storage = MmapStorage::create("map.bin", 16385).unwrap();
+/// let mut map = Builder::new(16385, 0.15)
+///     .seed("qwerty")
+///     .storage(Box::new(storage))
+///     .build();
+///
+/// map.gen_area((0, 0), (1024, 1024));
+///
+/// // Later, the generated cells are still there.
+/// let storage = MmapStorage::open("map.bin", 16385).unwrap();
+/// let map = Builder::new(16385, 0.15)
+///     .seed("qwerty")
+///     .init_by(InitBy::None)
+///     .storage(Box::new(storage))
+///     .build();
+///
+/// assert!(map.get(512, 512).is_some());
+/// ```
+#[derive(Debug)]
+pub struct MmapStorage {
+    width: i64,
+    height: i64,
+    mmap: MmapMut,
+}
+
+impl MmapStorage {
+    /// Creates the file at `path` (truncating it if it exists)
+    /// for a map with the side `size` and maps it into memory.
+    /// All cells of the new file are not generated.
+    pub fn create<P: AsRef<Path>>(path: P, size: i64) -> io::Result<Self> {
+        Self::create_rect(path, size, size)
+    }
+    /// The same as `create`, but for a map `width` points wide
+    /// and `height` points high.
+    pub fn create_rect<P: AsRef<Path>>(path: P, width: i64, height: i64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        file.set_len(Self::file_len(width, height)?)?;
+
+        Self::map(file, width, height)
+    }
+    /// Opens the file at `path` previously created by `create`
+    /// with the same `size` and maps it into memory.
+    pub fn open<P: AsRef<Path>>(path: P, size: i64) -> io::Result<Self> {
+        Self::open_rect(path, size, size)
+    }
+    /// The same as `open`, but for a map `width` points wide
+    /// and `height` points high.
+    pub fn open_rect<P: AsRef<Path>>(path: P, width: i64, height: i64) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        if file.metadata()?.len() != Self::file_len(width, height)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the file length does not match the map size",
+            ));
+        }
+
+        Self::map(file, width, height)
+    }
+    /// Getter for `width` field.
+    pub fn width(&self) -> i64 {
+        self.width
+    }
+    /// Getter for `height` field.
+    pub fn height(&self) -> i64 {
+        self.height
+    }
+    /// Writes all changed cells to the file. The changes are
+    /// also written when the storage is dropped, but without
+    /// the ability to handle errors.
+    pub fn flush(&self) -> io::Result<()> {
+        self.mmap.flush()
+    }
+
+    fn file_len(width: i64, height: i64) -> io::Result<u64> {
+        (width.max(0) as u64)
+            .checked_mul(height.max(0) as u64)
+            .and_then(|len| len.checked_mul(CELL_LEN as u64))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the map is too big"))
+    }
+
+    #[allow(unsafe_code)]
+    fn map(file: File, width: i64, height: i64) -> io::Result<Self> {
+        // The file must not be changed by anyone else while it
+        // is mapped, which is the contract of `MmapMut::map_mut`.
+        let mmap = unsafe { MmapMut::map_mut(&file)? };
+
+        Ok(Self {
+            width,
+            height,
+            mmap,
+        })
+    }
+
+    /// Returns a copy of the storage, which keeps the cells in
+    /// the memory, so the changes of the copy are not written to
+    /// the file. Fails if the memory for the copy can not be
+    /// allocated.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let mut mmap = MmapMut::map_anon(self.mmap.len())?;
+        mmap.copy_from_slice(&self.mmap);
+
+        Ok(Self {
+            width: self.width,
+            height: self.height,
+            mmap,
+        })
+    }
+
+    fn offset(&self, x: i64, y: i64) -> usize {
+        (y as usize * self.width as usize + x as usize) * CELL_LEN
+    }
+}
+
+impl Storage for MmapStorage {
+    fn get(&self, x: i64, y: i64) -> Option<f32> {
+        let offset = self.offset(x, y);
+        let mut raw = [0; CELL_LEN];
+        raw.copy_from_slice(&self.mmap[offset..offset + CELL_LEN]);
+
+        let h = f32::from_bits(!u32::from_le_bytes(raw));
+
+        if h.is_nan() {
+            None
+        } else {
+            Some(h)
+        }
+    }
+
+    fn set(&mut self, x: i64, y: i64, h: Option<f32>) -> Option<f32> {
+        let old = self.get(x, y);
+        let offset = self.offset(x, y);
+        let raw = match h {
+            Some(h) if !h.is_nan() => !h.to_bits(),
+            _ => 0,
+        };
+
+        self.mmap[offset..offset + CELL_LEN].copy_from_slice(&raw.to_le_bytes());
+
+        old
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Storage>> {
+        Ok(Box::new(MmapStorage::try_clone(self)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persists() {
+        let path = std::env::temp_dir().join(format!("lds_mmap_{}.bin", std::process::id()));
+
+        {
+            let mut storage = MmapStorage::create(&path, 9).unwrap();
+
+            assert_eq!(storage.get(8, 8), None);
+            assert_eq!(storage.set(8, 8, Some(0.5)), None);
+            assert_eq!(storage.set(8, 8, Some(0.25)), Some(0.5));
+            assert_eq!(storage.set(0, 1, Some(f32::NAN)), None);
+            storage.flush().unwrap();
+
+            let mut copy = storage.try_clone().unwrap();
+
+            assert_eq!(copy.set(8, 8, Some(1.0)), Some(0.25));
+            assert_eq!(storage.get(8, 8), Some(0.25));
+        }
+
+        assert!(MmapStorage::open(&path, 17).is_err());
+
+        let storage = MmapStorage::open(&path, 9).unwrap();
+
+        assert_eq!(storage.get(8, 8), Some(0.25));
+        assert_eq!(storage.get(0, 1), None);
+
+        drop(storage);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,345 @@
+use std::io;
+
+use seahash::hash_seeded;
+
+use crate::{Builder, HeightMap, Sample};
+
+/// A height map, which is the weighted sum of several
+/// `HeightMap`s (octaves) with their own seeds, sizes and
+/// roughness values, as in the fractional Brownian motion.
+///
+/// The point `(x, y)` of the sum is the point
+/// `(x * frequency, y * frequency)` of each octave. If these
+/// coordinates are fractional, the height of the octave is
+/// interpolated bilinearly between the nearest points. The
+/// points of an octave are generated only when a point of the
+/// sum, which needs them, is generated, and the coordinates
+/// outside an octave are changed by its edge mode.
+///
+/// The heights of the octaves are summed in `f64` in the range
+/// `0.0..=1.0` (see `Sample::to_f64`), so with the weights
+/// adding up to `1.0` the sum stays in the unit range. An octave
+/// added by `blend` is blended over the octaves added before it
+/// instead.
+///
+/// # Examples
+///
+/// ```
+/// use lazy_diamond_square::{Builder, Octaves};
+///
+/// let mut octaves = Octaves::new()
+///     .octave(Builder::new(65, 0.3).seed("base").build(), 0.75, 0.5)
+///     .octave(Builder::new(65, 0.6).seed("detail").build(), 0.25, 1.0);
+///
+/// assert_eq!(octaves.get(10, 10), None);
+/// assert_ne!(octaves.gen(10, 10), None);
+/// assert_eq!(octaves.get(10, 10), octaves.gen(10, 10));
+/// ```
+#[derive(Debug)]
+pub struct Octaves<H: Sample = f32> {
+    octaves: Vec<Octave<H>>,
+}
+
+#[derive(Debug)]
+struct Octave<H: Sample> {
+    map: HeightMap<H>,
+    weight: f32,
+    frequency: f64,
+    blend: bool,
+}
+
+impl<H: Sample> Octaves<H> {
+    /// Returns a sum without octaves.
+    pub fn new() -> Self {
+        Self {
+            octaves: Vec::new(),
+        }
+    }
+    /// Returns `count` octaves built by `builder` with seeds
+    /// derived from the seed of the builder. The octave `i` has
+    /// the frequency `lacunarity^i` and the weight proportional
+    /// to `gain^i`, and the weights add up to `1.0`.
+    ///
+    /// # Panics
+    ///
+    /// If the builder has a storage set by `Builder::storage`,
+    /// because each octave needs a storage of its own.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lazy_diamond_square::{Builder, Octaves};
+    ///
+    /// let mut octaves = Octaves::fbm(Builder::new(257, 0.3).seed("qwerty"), 4, 2.0, 0.5);
+    ///
+    /// assert_eq!(octaves.len(), 4);
+    /// assert!((0.0..=1.0).contains(&octaves.gen(10, 10).unwrap()));
+    /// ```
+    pub fn fbm(builder: Builder<H>, count: u32, lacunarity: f64, gain: f32) -> Self {
+        let total: f32 = (0..count).map(|i| gain.powi(i as i32)).sum();
+        let seed = builder.seed;
+
+        (0..count).fold(Self::new(), |octaves, i| {
+            let map = builder
+                .clone()
+                .seed_value(hash_seeded(b"octave", seed, i as u64, 0, 0))
+                .build();
+
+            octaves.octave(map, gain.powi(i as i32) / total, lacunarity.powi(i as i32))
+        })
+    }
+    /// Adds the octave `map`, whose heights are multiplied by
+    /// `weight`, and whose coordinates are the coordinates of the
+    /// sum multiplied by `frequency`.
+    pub fn octave(self, map: HeightMap<H>, weight: f32, frequency: f64) -> Self {
+        self.push(map, weight, frequency, false)
+    }
+    /// Adds the octave `map`, which is blended over the octaves
+    /// added before it: the height is `weight` times the height of
+    /// the octave plus `1 - weight` times the height of the
+    /// previous octaves. The coordinates of the octave are the
+    /// coordinates of the sum multiplied by `frequency`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lazy_diamond_square::{Builder, Octaves};
+    ///
+    /// let mut octaves = Octaves::new()
+    ///     .octave(Builder::new(65, 0.3).seed("plains").build(), 1.0, 0.5)
+    ///     .blend(Builder::new(65, 0.6).seed("hills").build(), 0.5, 1.0);
+    ///
+    /// assert!((0.0..=1.0).contains(&octaves.gen(10, 10).unwrap()));
+    /// ```
+    pub fn blend(self, map: HeightMap<H>, weight: f32, frequency: f64) -> Self {
+        self.push(map, weight, frequency, true)
+    }
+    fn push(self, map: HeightMap<H>, weight: f32, frequency: f64, blend: bool) -> Self {
+        let mut octaves = self.octaves;
+        octaves.push(Octave {
+            map,
+            weight,
+            frequency,
+            blend,
+        });
+
+        Self { octaves }
+    }
+    /// Returns a copy of the sum with copies of the octaves (see
+    /// `HeightMap::try_clone`).
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let octaves = self
+            .octaves
+            .iter()
+            .map(|octave| {
+                Ok(Octave {
+                    map: octave.map.try_clone()?,
+                    weight: octave.weight,
+                    frequency: octave.frequency,
+                    blend: octave.blend,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self { octaves })
+    }
+    /// Returns the number of octaves.
+    pub fn len(&self) -> usize {
+        self.octaves.len()
+    }
+    /// Returns `true` if there are no octaves.
+    pub fn is_empty(&self) -> bool {
+        self.octaves.is_empty()
+    }
+    /// Returns the map of the octave with the index `index` in
+    /// the order they were added.
+    pub fn map(&self, index: usize) -> Option<&HeightMap<H>> {
+        self.octaves.get(index).map(|octave| &octave.map)
+    }
+    /// Returns the height value at specified coordinates, or
+    /// `None` if one of the points of the octaves it needs is not
+    /// generated yet.
+    pub fn get(&self, x: i64, y: i64) -> Option<H> {
+        let mut sum = 0.0;
+
+        for octave in &self.octaves {
+            let map = &octave.map;
+            let h = interpolate(octave.frequency, x, y, |x, y| map.get(x, y))?;
+            sum = octave.add(sum, h);
+        }
+
+        Some(H::from_f64(sum))
+    }
+    /// Generates the points of the octaves needed for the height
+    /// value at specified coordinates, if they are not generated
+    /// yet, and returns it. Returns `None` if a point of an octave
+    /// cannot be generated (see `HeightMap::gen`).
+    pub fn gen(&mut self, x: i64, y: i64) -> Option<H> {
+        let mut sum = 0.0;
+
+        for octave in &mut self.octaves {
+            let map = &mut octave.map;
+            let h = interpolate(octave.frequency, x, y, |x, y| map.gen(x, y))?;
+            sum = octave.add(sum, h);
+        }
+
+        Some(H::from_f64(sum))
+    }
+    /// Returns a vector of the results of calls to the `get`
+    /// method for each point on the specified area.
+    pub fn get_area(
+        &self,
+        top_left: (i64, i64),
+        bottom_right: (i64, i64),
+    ) -> Vec<(i64, i64, Option<H>)> {
+        let mut area = Vec::new();
+
+        for y in top_left.1..bottom_right.1 {
+            for x in top_left.0..bottom_right.0 {
+                area.push((x, y, self.get(x, y)));
+            }
+        }
+
+        area
+    }
+    /// Returns a vector of the results of calls to the `gen`
+    /// method for each point on the specified area.
+    pub fn gen_area(
+        &mut self,
+        top_left: (i64, i64),
+        bottom_right: (i64, i64),
+    ) -> Vec<(i64, i64, Option<H>)> {
+        let mut area = Vec::new();
+
+        for y in top_left.1..bottom_right.1 {
+            for x in top_left.0..bottom_right.0 {
+                area.push((x, y, self.gen(x, y)));
+            }
+        }
+
+        area
+    }
+}
+
+impl<H: Sample> Octave<H> {
+    /// Adds the height `h` of the octave to the sum of the
+    /// previous octaves.
+    fn add(&self, sum: f64, h: f64) -> f64 {
+        let weight = self.weight as f64;
+
+        if self.blend {
+            weight * h + (1.0 - weight) * sum
+        } else {
+            sum + weight * h
+        }
+    }
+}
+
+impl<H: Sample> Default for Octaves<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the height of an octave at the point `(x, y)` of the
+/// sum, mapped onto `0.0..=1.0`. `h` returns the height of the
+/// octave at its own coordinates and is called only for the
+/// points with a non-zero weight.
+fn interpolate<H: Sample>(
+    frequency: f64,
+    x: i64,
+    y: i64,
+    mut h: impl FnMut(i64, i64) -> Option<H>,
+) -> Option<f64> {
+    let (x, y) = (x as f64 * frequency, y as f64 * frequency);
+    let (left, top) = (x.floor(), y.floor());
+    let (tx, ty) = (x - left, y - top);
+    let (left, top) = (left as i64, top as i64);
+
+    let mut value = 0.0;
+
+    for (dx, dy, w) in [
+        (0, 0, (1.0 - tx) * (1.0 - ty)),
+        (1, 0, tx * (1.0 - ty)),
+        (0, 1, (1.0 - tx) * ty),
+        (1, 1, tx * ty),
+    ] {
+        if w != 0.0 {
+            value += w * h(left + dx, top + dy)?.to_f64();
+        }
+    }
+
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_octave() {
+        let builder = Builder::new(65, 0.3).seed("single_octave");
+        let mut map = builder.clone().build();
+        let mut octaves = Octaves::new().octave(builder.build(), 1.0, 1.0);
+
+        for (x, y, h) in octaves.gen_area((0, 0), (64, 64)) {
+            assert_eq!(h, map.gen(x, y));
+        }
+    }
+
+    #[test]
+    fn frequency() {
+        let builder = Builder::<f64>::with_sample(65, 0.3).seed("frequency");
+        let mut map = builder.clone().build();
+        let mut octaves = Octaves::new().octave(builder.build(), 0.5, 0.5);
+
+        assert_eq!(octaves.gen(20, 6), map.gen(10, 3).map(|h| 0.5 * h));
+
+        // Only the points of the octave around the point of the
+        // sum are generated.
+        octaves.gen(21, 6);
+        assert!(octaves.map(0).unwrap().get(11, 3).is_some());
+        assert!(octaves.map(0).unwrap().get(11, 4).is_none());
+
+        let (a, b) = (map.gen(10, 3).unwrap(), map.gen(11, 3).unwrap());
+        let h = octaves.get(21, 6).unwrap();
+        assert!((h - 0.25 * (a + b)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn blend() {
+        let base = Builder::<f64>::with_sample(65, 0.3).seed("base");
+        let detail = Builder::<f64>::with_sample(65, 0.6).seed("detail");
+        let (mut a, mut b) = (base.clone().build(), detail.clone().build());
+        let mut octaves = Octaves::new()
+            .octave(base.clone().build(), 0.5, 1.0)
+            .octave(base.build(), 0.5, 1.0)
+            .blend(detail.clone().build(), 0.25, 1.0);
+        let mut last = Octaves::new().blend(detail.build(), 1.0, 1.0);
+
+        for (x, y, h) in octaves.gen_area((0, 0), (16, 16)) {
+            let (a, b) = (a.gen(x, y).unwrap(), b.gen(x, y).unwrap());
+
+            assert!((h.unwrap() - (0.25 * b + 0.75 * a)).abs() < 1e-12);
+            assert_eq!(last.gen(x, y), Some(b));
+        }
+    }
+
+    #[test]
+    fn fbm() {
+        let octaves = Octaves::fbm(
+            Builder::<u16>::with_sample(33, 0.3).seed("fbm"),
+            3,
+            2.0,
+            0.5,
+        );
+
+        assert_eq!(octaves.len(), 3);
+        assert_ne!(
+            octaves.map(0).unwrap().seed(),
+            octaves.map(1).unwrap().seed()
+        );
+        assert!((octaves.octaves.iter().map(|o| o.weight).sum::<f32>() - 1.0).abs() < 1e-6);
+        assert_eq!(octaves.octaves[2].frequency, 4.0);
+    }
+}
@@ -0,0 +1,349 @@
+use std::fmt;
+
+/// The type of one height value of a `HeightMap`.
+///
+/// Every sample type has a unit range, which the generated
+/// heights stay in, unless the map is unbounded: `0.0..=1.0`
+/// for `f32`, `f64` and `Fixed`, and `0..=u16::MAX` for `u16`.
+pub trait Sample: Copy + PartialEq + PartialOrd + fmt::Debug + Send + Sync + 'static {
+    /// Maps the full range of `u16` onto the unit range. It is
+    /// used for the random values and the initial heights.
+    fn from_u16(h: u16) -> Self;
+    /// Maps `0.0..=1.0` onto the unit range. The values outside
+    /// it are mapped outside the unit range, as far as the type
+    /// allows.
+    fn from_f32(h: f32) -> Self;
+    /// Returns the arithmetic mean of the heights. `heights` is
+    /// never empty.
+    fn mean(heights: &[Self]) -> Self;
+    /// Returns `r * rand + (1 - r) * h`, where `r` is in the
+    /// range `0.0..=1.0`.
+    fn blend(rand: Self, h: Self, r: f32) -> Self;
+    /// Returns `h + d`, where `d` is measured in the lengths of
+    /// the unit range. The result may be outside the unit range,
+    /// as far as the type allows.
+    fn displace(h: Self, d: f32) -> Self;
+    /// Clamps the height to the unit range.
+    fn clamp_unit(self) -> Self;
+    /// Maps the range `from.0..=from.1` linearly onto the range
+    /// `to.0..=to.1`. If `from` is a single value, returns
+    /// `to.0`.
+    fn rescale(self, from: (Self, Self), to: (Self, Self)) -> Self;
+    /// Maps the unit range onto `0.0..=1.0`.
+    fn to_f32(self) -> f32;
+    /// The same as `from_f32`, but for `f64`, so the types with
+    /// more precision than `f32` keep it.
+    fn from_f64(h: f64) -> Self {
+        Self::from_f32(h as f32)
+    }
+    /// The same as `to_f32`, but for `f64`, so the types with
+    /// more precision than `f32` keep it.
+    fn to_f64(self) -> f64 {
+        self.to_f32() as f64
+    }
+}
+
+impl Sample for f32 {
+    fn from_u16(h: u16) -> Self {
+        crate::HeightMap::<f32>::to_range(0.0, 1.0, h)
+    }
+
+    fn from_f32(h: f32) -> Self {
+        h
+    }
+
+    fn mean(heights: &[Self]) -> Self {
+        heights.iter().sum::<f32>() / heights.len() as f32
+    }
+
+    fn blend(rand: Self, h: Self, r: f32) -> Self {
+        (r * rand) + (1.0 - r) * h
+    }
+
+    fn displace(h: Self, d: f32) -> Self {
+        h + d
+    }
+
+    fn clamp_unit(self) -> Self {
+        self.clamp(0.0, 1.0)
+    }
+
+    fn rescale(self, from: (Self, Self), to: (Self, Self)) -> Self {
+        if from.0 == from.1 {
+            return to.0;
+        }
+
+        to.0 + (self - from.0) * (to.1 - to.0) / (from.1 - from.0)
+    }
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+}
+
+impl Sample for f64 {
+    fn from_u16(h: u16) -> Self {
+        h as f64 / u16::MAX as f64
+    }
+
+    fn from_f32(h: f32) -> Self {
+        h as f64
+    }
+
+    fn mean(heights: &[Self]) -> Self {
+        heights.iter().sum::<f64>() / heights.len() as f64
+    }
+
+    fn blend(rand: Self, h: Self, r: f32) -> Self {
+        let r = r as f64;
+
+        (r * rand) + (1.0 - r) * h
+    }
+
+    fn displace(h: Self, d: f32) -> Self {
+        h + d as f64
+    }
+
+    fn clamp_unit(self) -> Self {
+        self.clamp(0.0, 1.0)
+    }
+
+    fn rescale(self, from: (Self, Self), to: (Self, Self)) -> Self {
+        if from.0 == from.1 {
+            return to.0;
+        }
+
+        to.0 + (self - from.0) * (to.1 - to.0) / (from.1 - from.0)
+    }
+
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f64(h: f64) -> Self {
+        h
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+impl Sample for u16 {
+    fn from_u16(h: u16) -> Self {
+        h
+    }
+
+    fn from_f32(h: f32) -> Self {
+        (h * u16::MAX as f32).round().clamp(0.0, u16::MAX as f32) as u16
+    }
+
+    fn mean(heights: &[Self]) -> Self {
+        let sum: u64 = heights.iter().map(|&h| h as u64).sum();
+
+        (sum / heights.len() as u64) as u16
+    }
+
+    fn blend(rand: Self, h: Self, r: f32) -> Self {
+        let r = to_q16(r);
+
+        ((r * rand as u64 + (FIXED_ONE as u64 - r) * h as u64) >> FIXED_SHIFT) as u16
+    }
+
+    fn displace(h: Self, d: f32) -> Self {
+        let d = to_signed_q16(d).saturating_mul(u16::MAX as i64) >> FIXED_SHIFT;
+
+        (h as i64).saturating_add(d).clamp(0, u16::MAX as i64) as u16
+    }
+
+    fn clamp_unit(self) -> Self {
+        self
+    }
+
+    fn rescale(self, from: (Self, Self), to: (Self, Self)) -> Self {
+        let h = rescale_int(
+            self as i64,
+            (from.0 as i64, from.1 as i64),
+            (to.0 as i64, to.1 as i64),
+        );
+
+        h.clamp(0, u16::MAX as i64) as u16
+    }
+
+    fn to_f32(self) -> f32 {
+        self as f32 / u16::MAX as f32
+    }
+
+    fn from_f64(h: f64) -> Self {
+        (h * u16::MAX as f64).round().clamp(0.0, u16::MAX as f64) as u16
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64 / u16::MAX as f64
+    }
+}
+
+const FIXED_SHIFT: u8 = 16;
+const FIXED_ONE: i32 = 1 << FIXED_SHIFT;
+
+/// A signed fixed-point number with 16 fractional bits.
+///
+/// With `Displacement::Blend` and `Distribution::Uniform` (the
+/// default) all the arithmetic of the generation of `Fixed`
+/// heights is done in integers, except for the roughness, which
+/// is rounded to 16 fractional bits, so the same map is
+/// generated on every platform. `Displacement::Classic` and the
+/// other distributions compute the displacement in floating
+/// point with `exp2`, `ln`, `cos` and the like, whose results
+/// may differ in the last bits between platforms.
+///
+/// # Examples
+///
+/// ```
+/// use lazy_diamond_square::{Builder, Fixed};
+///
+/// let mut map = Builder::<Fixed>::with_sample(65, 0.15)
+///     .seed("qwerty")
+///     .build();
+///
+/// let h = map.gen(10, 10).unwrap();
+///
+/// assert!((Fixed::ZERO..=Fixed::ONE).contains(&h));
+/// ```
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct Fixed(pub i32);
+
+impl Fixed {
+    /// `0.0`
+    pub const ZERO: Fixed = Fixed(0);
+    /// `1.0`
+    pub const ONE: Fixed = Fixed(FIXED_ONE);
+
+    /// Converts the number to `f64`.
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / FIXED_ONE as f64
+    }
+}
+
+impl Sample for Fixed {
+    fn from_u16(h: u16) -> Self {
+        Fixed((((h as i64) << FIXED_SHIFT) / u16::MAX as i64) as i32)
+    }
+
+    fn from_f32(h: f32) -> Self {
+        Fixed((h as f64 * FIXED_ONE as f64).round() as i32)
+    }
+
+    fn mean(heights: &[Self]) -> Self {
+        let sum: i64 = heights.iter().map(|h| h.0 as i64).sum();
+
+        Fixed(sum.div_euclid(heights.len() as i64) as i32)
+    }
+
+    fn blend(rand: Self, h: Self, r: f32) -> Self {
+        let r = to_q16(r) as i64;
+
+        Fixed(((r * rand.0 as i64 + (FIXED_ONE as i64 - r) * h.0 as i64) >> FIXED_SHIFT) as i32)
+    }
+
+    fn displace(h: Self, d: f32) -> Self {
+        Fixed(
+            (h.0 as i64)
+                .saturating_add(to_signed_q16(d))
+                .clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+        )
+    }
+
+    fn clamp_unit(self) -> Self {
+        self.clamp(Fixed::ZERO, Fixed::ONE)
+    }
+
+    fn rescale(self, from: (Self, Self), to: (Self, Self)) -> Self {
+        let h = rescale_int(
+            self.0 as i64,
+            (from.0 .0 as i64, from.1 .0 as i64),
+            (to.0 .0 as i64, to.1 .0 as i64),
+        );
+
+        Fixed(h.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+    }
+
+    fn to_f32(self) -> f32 {
+        self.to_f64() as f32
+    }
+
+    fn from_f64(h: f64) -> Self {
+        Fixed((h * FIXED_ONE as f64).round() as i32)
+    }
+
+    fn to_f64(self) -> f64 {
+        Fixed::to_f64(self)
+    }
+}
+
+fn to_q16(r: f32) -> u64 {
+    (r.clamp(0.0, 1.0) * FIXED_ONE as f32).round() as u64
+}
+
+fn to_signed_q16(d: f32) -> i64 {
+    (d as f64 * FIXED_ONE as f64).round() as i64
+}
+
+/// `rescale` of integers, rounded toward negative infinity.
+fn rescale_int(h: i64, from: (i64, i64), to: (i64, i64)) -> i64 {
+    if from.0 == from.1 {
+        return to.0;
+    }
+
+    let h = (h - from.0) as i128 * (to.1 - to.0) as i128;
+
+    to.0 + h.div_euclid((from.1 - from.0) as i128) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_range() {
+        assert_eq!(f64::from_u16(u16::MAX), 1.0);
+        assert_eq!(Fixed::from_u16(u16::MAX), Fixed::ONE);
+        assert_eq!(Fixed::from_u16(0), Fixed::ZERO);
+        assert_eq!(u16::mean(&[u16::MAX; 4]), u16::MAX);
+        assert_eq!(u16::blend(u16::MAX, 0, 1.0), u16::MAX);
+        assert_eq!(
+            Fixed::blend(Fixed::ONE, Fixed::ZERO, 0.5),
+            Fixed(FIXED_ONE / 2)
+        );
+        assert_eq!(Fixed::mean(&[Fixed(1), Fixed(2)]), Fixed(1));
+        assert_eq!(u16::displace(u16::MAX, 1.0), u16::MAX);
+        assert_eq!(u16::displace(u16::MAX, -1.0), 0);
+        assert_eq!(Fixed::displace(Fixed::ONE, -0.5), Fixed(FIXED_ONE / 2));
+        assert_eq!(Fixed::displace(Fixed::ZERO, -0.5), Fixed(-FIXED_ONE / 2));
+        assert_eq!(f32::displace(0.75, 0.5).clamp_unit(), 1.0);
+        assert_eq!(u16::from_f32(2.0), u16::MAX);
+        assert_eq!(Fixed::from_f32(-1.5), Fixed(-FIXED_ONE * 3 / 2));
+    }
+
+    #[test]
+    fn rescale() {
+        assert_eq!((-1.0f32).rescale((-1.0, 3.0), (0.0, 1.0)), 0.0);
+        assert_eq!(1.0f64.rescale((-1.0, 3.0), (0.0, 1.0)), 0.5);
+        assert_eq!(0.5f32.rescale((0.5, 0.5), (0.0, 1.0)), 0.0);
+        assert_eq!(300u16.rescale((100, 300), (0, u16::MAX)), u16::MAX);
+        assert_eq!(
+            Fixed(-FIXED_ONE).rescale(
+                (Fixed(-FIXED_ONE), Fixed(FIXED_ONE)),
+                (Fixed::ZERO, Fixed::ONE)
+            ),
+            Fixed::ZERO
+        );
+        assert_eq!(
+            Fixed::ZERO.rescale(
+                (Fixed(-FIXED_ONE), Fixed(FIXED_ONE)),
+                (Fixed::ZERO, Fixed::ONE)
+            ),
+            Fixed(FIXED_ONE / 2)
+        );
+    }
+}
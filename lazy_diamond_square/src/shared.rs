@@ -0,0 +1,183 @@
+use std::fmt;
+use std::sync::{Mutex, PoisonError};
+
+use crate::storage::LockedStorage;
+use crate::{widen, HeightMap, Sample};
+
+/// A height map, which can be shared between threads: `get`,
+/// `set` and `gen` take `&self`, so many threads can generate
+/// the map at once. It is created by `Builder::build_shared`.
+///
+/// The map is kept in tiles of `CHUNK_SIZE` by `CHUNK_SIZE`
+/// cells, each behind its own lock, and the locks are held only
+/// while a cell is read or written. Two threads may generate
+/// the same point at once, but they get the same value, so the
+/// result does not depend on how the work is split between
+/// the threads.
+///
+/// # Examples
+///
+/// ```
+/// use std::thread;
+///
+/// use lazy_diamond_square::Builder;
+///
+/// let map = Builder::new(257, 0.15).seed("qwerty").build_shared();
+///
+/// thread::scope(|s| {
+///     for i in 0..4 {
+///         let map = &map;
+///
+///         s.spawn(move || map.gen_area((i * 64, 0), (i * 64 + 64, 256)));
+///     }
+/// });
+///
+/// assert!(map.get(200, 100).is_some());
+/// ```
+pub struct SharedHeightMap<H: Sample = f32> {
+    map: HeightMap<H>,
+    cells: LockedStorage<H>,
+    bounds: Mutex<Option<(H, H)>>,
+}
+
+impl<H: Sample> SharedHeightMap<H> {
+    pub(crate) fn new(map: HeightMap<H>, cells: LockedStorage<H>) -> Self {
+        let bounds = Mutex::new(map.bounds());
+
+        Self { map, cells, bounds }
+    }
+    /// Returns the map, which holds the settings of this map and
+    /// shares its cells, for example to call the getters of the
+    /// settings.
+    pub fn map(&self) -> &HeightMap<H> {
+        &self.map
+    }
+    /// Returns the number of allocated tiles.
+    pub fn chunks(&self) -> usize {
+        self.cells.chunks()
+    }
+    /// The same as `HeightMap::bounds`.
+    pub fn bounds(&self) -> Option<(H, H)> {
+        *self.bounds.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// The same as `HeightMap::get`.
+    pub fn get(&self, x: i64, y: i64) -> Option<H> {
+        self.map.get(x, y)
+    }
+    /// The same as `HeightMap::set`.
+    pub fn set(&self, x: i64, y: i64, h: Option<H>) -> Option<H> {
+        let (x, y) = self.map.to_valid_coords(x, y);
+
+        if let Some(h) = h {
+            self.widen(Some((h, h)));
+        }
+
+        self.cells.set(x, y, h)
+    }
+    /// The same as `HeightMap::gen`.
+    pub fn gen(&self, x: i64, y: i64) -> Option<H> {
+        let mut bounds = None;
+        let h = self.map.gen_in(&mut self.cells.share(), &mut bounds, x, y);
+        self.widen(bounds);
+
+        h
+    }
+    /// Returns a vector of the results of calls to the `get`
+    /// method for each point on the specified area.
+    pub fn get_area(
+        &self,
+        top_left: (i64, i64),
+        bottom_right: (i64, i64),
+    ) -> Vec<(i64, i64, Option<H>)> {
+        self.map.get_area(top_left, bottom_right)
+    }
+    /// Returns a vector of the results of calls to the `gen`
+    /// method for each point on the specified area.
+    pub fn gen_area(
+        &self,
+        top_left: (i64, i64),
+        bottom_right: (i64, i64),
+    ) -> Vec<(i64, i64, Option<H>)> {
+        let (top_left, bottom_right) = (
+            self.map.to_valid_coords(top_left.0, top_left.1),
+            self.map.to_valid_coords(bottom_right.0, bottom_right.1),
+        );
+        let mut cells = self.cells.share();
+        let mut bounds = None;
+        let mut area = Vec::new();
+
+        for y in top_left.1..bottom_right.1 {
+            for x in top_left.0..bottom_right.0 {
+                area.push((x, y, self.map.gen_in(&mut cells, &mut bounds, x, y)));
+            }
+        }
+
+        self.widen(bounds);
+
+        area
+    }
+
+    /// Widens the bounds of the map by the bounds of the heights
+    /// written by one call.
+    fn widen(&self, bounds: Option<(H, H)>) {
+        if let Some((min, max)) = bounds {
+            let mut map_bounds = self.bounds.lock().unwrap_or_else(PoisonError::into_inner);
+            widen(&mut map_bounds, min);
+            widen(&mut map_bounds, max);
+        }
+    }
+}
+
+impl<H: Sample> fmt::Debug for SharedHeightMap<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedHeightMap")
+            .field("map", &self.map)
+            .field("chunks", &self.chunks())
+            .field("bounds", &self.bounds())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Builder, EdgeMode};
+
+    #[test]
+    fn send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<HeightMap>();
+        assert_send_sync::<HeightMap<u16>>();
+        assert_send_sync::<SharedHeightMap>();
+    }
+
+    #[test]
+    fn concurrent_gen() {
+        let builder = || {
+            Builder::new(129, 0.15)
+                .seed("concurrent_gen")
+                .squares(2, 1)
+                .edge_mode(EdgeMode::Mirror)
+        };
+        let shared = builder().build_shared();
+        let mut single = builder().build();
+
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                let shared = &shared;
+
+                // The areas overlap, so the threads race for the
+                // same points.
+                s.spawn(move || shared.gen_area((i * 48, 0), (i * 48 + 112, 128)));
+            }
+        });
+
+        let area = single.gen_area((0, 0), (256, 128));
+
+        assert_eq!(area, shared.get_area((0, 0), (256, 128)));
+        let (min, max) = single.area_bounds((0, 0), (256, 128)).unwrap();
+        let (shared_min, shared_max) = shared.bounds().unwrap();
+        assert!(shared_min <= min && shared_max >= max);
+    }
+}
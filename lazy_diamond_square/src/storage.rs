@@ -0,0 +1,788 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+
+use crate::Sample;
+
+const CHUNK_SHIFT: u8 = 6;
+/// The side of one tile of a `ChunkedStorage`. Tiles are
+/// allocated only when a value is first written into them.
+///
+/// # Examples
+///
+/// ```
+/// use lazy_diamond_square::CHUNK_SIZE;
+///
+/// assert_eq!(CHUNK_SIZE, 64)
+/// ```
+pub const CHUNK_SIZE: i64 = 1 << CHUNK_SHIFT;
+
+const CHUNK_LEN: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+type Chunk<H> = Box<[Option<H>]>;
+type LockedChunks<H> = RwLock<HashMap<(i64, i64), RwLock<Chunk<H>>>>;
+
+/// Storage of the cells of a height map. `HeightMap` converts
+/// all coordinates to valid ones (`x` in the range
+/// `0..=max_x()` and `y` in the range `0..=max_y()`) before
+/// passing them here, so an implementation does not need to
+/// wrap them by itself. `H` is
+/// the type of the height values (see `Sample`). A storage must
+/// be `Send` and `Sync`, so the map can be shared between
+/// threads.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::BTreeMap;
+///
+/// use lazy_diamond_square::{Builder, Storage};
+///
+/// #[derive(Debug, Default)]
+/// struct Tree(BTreeMap<(i64, i64), f32>);
+///
+/// impl Storage for Tree {
+///     fn get(&self, x: i64, y: i64) -> Option<f32> {
+///         self.0.get(&(x, y)).copied()
+///     }
+///     fn set(&mut self, x: i64, y: i64, h: Option<f32>) -> Option<f32> {
+///         match h {
+///             Some(h) => self.0.insert((x, y), h),
+///             None => self.0.remove(&(x, y)),
+///         }
+///     }
+/// }
+///
+/// let mut map = Builder::new(65, 0.15)
+///     .storage(Box::new(Tree::default()))
+///     .build();
+///
+/// assert!(map.gen(10, 10).is_some());
+/// // `Tree` does not implement `try_clone`.
+/// assert!(map.try_clone().is_err());
+/// ```
+pub trait Storage<H: Sample = f32>: fmt::Debug + Send + Sync {
+    /// Returns the height value at specified coordinates.
+    fn get(&self, x: i64, y: i64) -> Option<H>;
+    /// Sets the passed value at specified coordinates and
+    /// returns the value that was there before.
+    fn set(&mut self, x: i64, y: i64, h: Option<H>) -> Option<H>;
+    /// Returns `true` if the height value at specified
+    /// coordinates is already generated or set.
+    fn is_generated(&self, x: i64, y: i64) -> bool {
+        self.get(x, y).is_some()
+    }
+    /// Returns a copy of the storage with the same cells, which
+    /// is used by `HeightMap::try_clone` and `Builder::clone`. By
+    /// default the storage can not be copied, and an error of the
+    /// kind `ErrorKind::Unsupported` is returned.
+    fn try_clone(&self) -> io::Result<Box<dyn Storage<H>>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the storage can not be copied",
+        ))
+    }
+}
+
+/// The default storage, which keeps all the cells of the map
+/// in one vector allocated up front.
+#[derive(Debug, Clone)]
+pub struct DenseStorage<H: Sample = f32> {
+    width: i64,
+    cells: Vec<Option<H>>,
+}
+
+impl<H: Sample> DenseStorage<H> {
+    /// Returns a new storage for a map with the side `size`.
+    pub fn new(size: i64) -> Self {
+        Self::rect(size, size)
+    }
+    /// Returns a new storage for a map `width` points wide and
+    /// `height` points high.
+    pub fn rect(width: i64, height: i64) -> Self {
+        Self {
+            width,
+            cells: vec![None; dense_len(width, height)],
+        }
+    }
+
+    fn index(&self, x: i64, y: i64) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+}
+
+impl<H: Sample> Default for DenseStorage<H> {
+    fn default() -> Self {
+        Self::rect(0, 0)
+    }
+}
+
+impl<H: Sample> Storage<H> for DenseStorage<H> {
+    fn get(&self, x: i64, y: i64) -> Option<H> {
+        self.cells[self.index(x, y)]
+    }
+
+    fn set(&mut self, x: i64, y: i64, h: Option<H>) -> Option<H> {
+        let index = self.index(x, y);
+
+        std::mem::replace(&mut self.cells[index], h)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Storage<H>>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// The dense storage, which keeps each cell in 4 bytes. A
+/// not generated cell is stored as NaN, so setting a NaN
+/// height is the same as setting `None`.
+#[derive(Debug, Default, Clone)]
+pub struct NanStorage {
+    width: i64,
+    cells: Vec<f32>,
+}
+
+impl NanStorage {
+    /// Returns a new storage for a map with the side `size`.
+    pub fn new(size: i64) -> Self {
+        Self::rect(size, size)
+    }
+    /// Returns a new storage for a map `width` points wide and
+    /// `height` points high.
+    pub fn rect(width: i64, height: i64) -> Self {
+        Self {
+            width,
+            cells: vec![f32::NAN; dense_len(width, height)],
+        }
+    }
+
+    fn index(&self, x: i64, y: i64) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+}
+
+impl Storage for NanStorage {
+    fn get(&self, x: i64, y: i64) -> Option<f32> {
+        let h = self.cells[self.index(x, y)];
+
+        if h.is_nan() {
+            None
+        } else {
+            Some(h)
+        }
+    }
+
+    fn set(&mut self, x: i64, y: i64, h: Option<f32>) -> Option<f32> {
+        let old = self.get(x, y);
+        let index = self.index(x, y);
+        self.cells[index] = h.unwrap_or(f32::NAN);
+
+        old
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Storage>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// The dense storage, which keeps each cell in 2 bytes
+/// quantized over the range `min..=max` plus one bit of an
+/// occupancy bitmap. Heights outside the range are clamped,
+/// and the value read back may differ from the written one by
+/// up to `(max - min) / 65535`.
+#[derive(Debug, Default, Clone)]
+pub struct QuantizedStorage {
+    width: i64,
+    min: f32,
+    max: f32,
+    cells: Vec<u16>,
+    generated: Vec<u64>,
+}
+
+impl QuantizedStorage {
+    /// Returns a new storage for a map with the side `size`
+    /// and heights in the range `min..=max`.
+    pub fn new(size: i64, min: f32, max: f32) -> Self {
+        Self::rect(size, size, min, max)
+    }
+    /// Returns a new storage for a map `width` points wide and
+    /// `height` points high with heights in the range
+    /// `min..=max`.
+    pub fn rect(width: i64, height: i64, min: f32, max: f32) -> Self {
+        let len = dense_len(width, height);
+
+        Self {
+            width,
+            min,
+            max,
+            cells: vec![0; len],
+            generated: vec![0; len.div_ceil(64)],
+        }
+    }
+
+    fn index(&self, x: i64, y: i64) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+
+    fn encode(&self, h: f32) -> u16 {
+        let range = self.max - self.min;
+        let h = ((h - self.min) / range).clamp(0.0, 1.0);
+
+        (h * u16::MAX as f32).round() as u16
+    }
+
+    fn decode(&self, h: u16) -> f32 {
+        h as f32 / u16::MAX as f32 * (self.max - self.min) + self.min
+    }
+}
+
+impl Storage for QuantizedStorage {
+    fn get(&self, x: i64, y: i64) -> Option<f32> {
+        let index = self.index(x, y);
+
+        if self.generated[index / 64] & (1 << (index % 64)) != 0 {
+            Some(self.decode(self.cells[index]))
+        } else {
+            None
+        }
+    }
+
+    fn set(&mut self, x: i64, y: i64, h: Option<f32>) -> Option<f32> {
+        let old = self.get(x, y);
+        let index = self.index(x, y);
+        let bit = 1 << (index % 64);
+
+        match h {
+            Some(h) => {
+                self.cells[index] = self.encode(h);
+                self.generated[index / 64] |= bit;
+            }
+            None => self.generated[index / 64] &= !bit,
+        }
+
+        old
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Storage>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// The storage, which keeps the map in tiles of `CHUNK_SIZE`
+/// by `CHUNK_SIZE` cells. Each tile is allocated only when a
+/// value is first written into it.
+#[derive(Debug, Clone)]
+pub struct ChunkedStorage<H: Sample = f32> {
+    chunks: HashMap<(i64, i64), Chunk<H>>,
+}
+
+impl<H: Sample> ChunkedStorage<H> {
+    /// Returns a new empty storage.
+    pub fn new() -> Self {
+        Self {
+            chunks: HashMap::new(),
+        }
+    }
+    /// Returns the number of allocated tiles.
+    pub fn chunks(&self) -> usize {
+        self.chunks.len()
+    }
+}
+
+impl<H: Sample> Default for ChunkedStorage<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: Sample> Storage<H> for ChunkedStorage<H> {
+    fn get(&self, x: i64, y: i64) -> Option<H> {
+        self.chunks
+            .get(&chunk_key(x, y))
+            .and_then(|chunk| chunk[chunk_index(x, y)])
+    }
+
+    fn set(&mut self, x: i64, y: i64, h: Option<H>) -> Option<H> {
+        let key = chunk_key(x, y);
+        let index = chunk_index(x, y);
+
+        if h.is_none() {
+            // Clearing a cell must not allocate a tile.
+            return self
+                .chunks
+                .get_mut(&key)
+                .and_then(|chunk| chunk[index].take());
+        }
+
+        let chunk = self.chunks.entry(key).or_insert_with(new_chunk);
+
+        std::mem::replace(&mut chunk[index], h)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Storage<H>>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+const MIN_CACHED_CHUNKS: usize = 8;
+/// A cell kept aside in a hash map takes about 4 times the
+/// memory of a cell of a tile.
+const ASIDE_PER_CHUNK: usize = CHUNK_LEN / 4;
+
+/// The storage, which keeps at most `max_chunks` tiles of
+/// `CHUNK_SIZE` by `CHUNK_SIZE` cells and drops the least
+/// recently used tile to allocate a new one. `HeightMap::gen`
+/// generates the dropped cells again with the same values,
+/// but the values written by `HeightMap::set` into a dropped
+/// tile are lost.
+///
+/// The cells on the grid of `CHUNK_SIZE` are kept aside, so a
+/// dropped tile is generated again from the nearest cells.
+/// They take the place of one tile, and the least recently used
+/// half of them is dropped when there is no more room.
+///
+/// The cells whose both coordinates are multiples of
+/// `pin_step` are kept aside too and are never dropped. These
+/// are the cells set at initialization, which can not be
+/// generated again. Every `CHUNK_SIZE * CHUNK_SIZE / 4` of them
+/// take the place of one more tile, so the memory stays within
+/// `max_chunks` tiles as long as they fit into it.
+#[derive(Debug)]
+pub struct CachedStorage<H: Sample = f32> {
+    max_chunks: usize,
+    pin_step: i64,
+    pinned: HashMap<(i64, i64), H>,
+    grid: HashMap<(i64, i64), (AtomicU64, H)>,
+    chunks: HashMap<(i64, i64), (AtomicU64, Chunk<H>)>,
+    clock: AtomicU64,
+}
+
+impl<H: Sample> CachedStorage<H> {
+    /// Returns a new empty storage. `max_chunks` less than 8
+    /// is changed to 8, so that generation of one point can
+    /// always keep its parents. At least 4 tiles are kept besides
+    /// the cells kept aside. `pin_step` less than 1 is changed to
+    /// 1.
+    pub fn new(max_chunks: usize, pin_step: i64) -> Self {
+        Self {
+            max_chunks: max_chunks.max(MIN_CACHED_CHUNKS),
+            pin_step: pin_step.max(1),
+            pinned: HashMap::new(),
+            grid: HashMap::new(),
+            chunks: HashMap::new(),
+            clock: AtomicU64::new(0),
+        }
+    }
+    /// Getter for `max_chunks` field.
+    pub fn max_chunks(&self) -> usize {
+        self.max_chunks
+    }
+    /// Returns the number of allocated tiles.
+    pub fn chunks(&self) -> usize {
+        self.chunks.len()
+    }
+    /// Returns the number of cells kept aside: the pinned ones
+    /// and the ones on the grid of `CHUNK_SIZE`.
+    pub fn kept_aside(&self) -> usize {
+        self.pinned.len() + self.grid.len()
+    }
+
+    fn is_pinned(&self, x: i64, y: i64) -> bool {
+        x % self.pin_step == 0 && y % self.pin_step == 0
+    }
+
+    fn is_on_grid(x: i64, y: i64) -> bool {
+        (x | y) & (CHUNK_SIZE - 1) == 0
+    }
+
+    /// Returns the number of tiles, which may be allocated next
+    /// to the cells kept aside.
+    fn chunk_limit(&self) -> usize {
+        let pinned = self.pinned.len().div_ceil(ASIDE_PER_CHUNK);
+
+        self.max_chunks
+            .saturating_sub(pinned + 1)
+            .max(MIN_CACHED_CHUNKS / 2)
+    }
+
+    fn touch(clock: &AtomicU64, last_used: &AtomicU64) {
+        let now = clock.fetch_add(1, Ordering::Relaxed) + 1;
+
+        last_used.store(now, Ordering::Relaxed);
+    }
+
+    fn evict(&mut self) {
+        let lru = self
+            .chunks
+            .iter()
+            .min_by_key(|(_, (last_used, _))| last_used.load(Ordering::Relaxed))
+            .map(|(key, _)| *key);
+
+        if let Some(key) = lru {
+            self.chunks.remove(&key);
+        }
+    }
+
+    /// Drops the least recently used half of the cells on the
+    /// grid.
+    fn evict_grid(&mut self) {
+        let mut last_used: Vec<_> = self
+            .grid
+            .values()
+            .map(|(last_used, _)| last_used.load(Ordering::Relaxed))
+            .collect();
+        let half = last_used.len() / 2;
+        let (_, &mut median, _) = last_used.select_nth_unstable(half);
+
+        self.grid
+            .retain(|_, (last_used, _)| last_used.load(Ordering::Relaxed) >= median);
+    }
+}
+
+impl<H: Sample> Clone for CachedStorage<H> {
+    fn clone(&self) -> Self {
+        let load = |clock: &AtomicU64| AtomicU64::new(clock.load(Ordering::Relaxed));
+
+        Self {
+            max_chunks: self.max_chunks,
+            pin_step: self.pin_step,
+            pinned: self.pinned.clone(),
+            grid: self
+                .grid
+                .iter()
+                .map(|(&key, (last_used, h))| (key, (load(last_used), *h)))
+                .collect(),
+            chunks: self
+                .chunks
+                .iter()
+                .map(|(&key, (last_used, chunk))| (key, (load(last_used), chunk.clone())))
+                .collect(),
+            clock: load(&self.clock),
+        }
+    }
+}
+
+impl<H: Sample> Storage<H> for CachedStorage<H> {
+    fn get(&self, x: i64, y: i64) -> Option<H> {
+        if self.is_pinned(x, y) {
+            return self.pinned.get(&(x, y)).copied();
+        }
+
+        if Self::is_on_grid(x, y) {
+            let (last_used, h) = self.grid.get(&(x, y))?;
+            Self::touch(&self.clock, last_used);
+
+            return Some(*h);
+        }
+
+        let (last_used, chunk) = self.chunks.get(&chunk_key(x, y))?;
+        Self::touch(&self.clock, last_used);
+
+        chunk[chunk_index(x, y)]
+    }
+
+    fn set(&mut self, x: i64, y: i64, h: Option<H>) -> Option<H> {
+        if self.is_pinned(x, y) {
+            let old = match h {
+                Some(h) => self.pinned.insert((x, y), h),
+                None => self.pinned.remove(&(x, y)),
+            };
+
+            while self.chunks.len() > self.chunk_limit() {
+                self.evict();
+            }
+
+            return old;
+        }
+
+        if Self::is_on_grid(x, y) {
+            let Some(h) = h else {
+                return self.grid.remove(&(x, y)).map(|(_, h)| h);
+            };
+
+            if !self.grid.contains_key(&(x, y)) && self.grid.len() >= ASIDE_PER_CHUNK {
+                self.evict_grid();
+            }
+
+            let clock = AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed) + 1);
+
+            return self.grid.insert((x, y), (clock, h)).map(|(_, h)| h);
+        }
+
+        let key = chunk_key(x, y);
+        let index = chunk_index(x, y);
+
+        if h.is_none() {
+            return self
+                .chunks
+                .get_mut(&key)
+                .and_then(|(_, chunk)| chunk[index].take());
+        }
+
+        if !self.chunks.contains_key(&key) && self.chunks.len() >= self.chunk_limit() {
+            self.evict();
+        }
+
+        let (last_used, chunk) = self
+            .chunks
+            .entry(key)
+            .or_insert_with(|| (AtomicU64::new(0), new_chunk()));
+        Self::touch(&self.clock, last_used);
+
+        std::mem::replace(&mut chunk[index], h)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Storage<H>>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// The storage of `SharedHeightMap`, which keeps the map in
+/// tiles of `CHUNK_SIZE` by `CHUNK_SIZE` cells, each behind its
+/// own lock, so the threads working on different tiles do not
+/// wait for each other.
+#[derive(Debug)]
+pub(crate) struct LockedStorage<H: Sample = f32> {
+    chunks: Arc<LockedChunks<H>>,
+}
+
+impl<H: Sample> LockedStorage<H> {
+    pub(crate) fn new() -> Self {
+        Self {
+            chunks: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Returns a handle to the same cells.
+    pub(crate) fn share(&self) -> Self {
+        Self {
+            chunks: Arc::clone(&self.chunks),
+        }
+    }
+
+    pub(crate) fn chunks(&self) -> usize {
+        self.chunks
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub(crate) fn get(&self, x: i64, y: i64) -> Option<H> {
+        let chunks = self.chunks.read().unwrap_or_else(PoisonError::into_inner);
+        let chunk = chunks.get(&chunk_key(x, y))?;
+
+        let h = chunk.read().unwrap_or_else(PoisonError::into_inner)[chunk_index(x, y)];
+
+        h
+    }
+
+    pub(crate) fn set(&self, x: i64, y: i64, h: Option<H>) -> Option<H> {
+        let key = chunk_key(x, y);
+        let index = chunk_index(x, y);
+
+        {
+            let chunks = self.chunks.read().unwrap_or_else(PoisonError::into_inner);
+
+            if let Some(chunk) = chunks.get(&key) {
+                let mut chunk = chunk.write().unwrap_or_else(PoisonError::into_inner);
+
+                return std::mem::replace(&mut chunk[index], h);
+            } else if h.is_none() {
+                return None;
+            }
+        }
+
+        // The tile is missing, so the whole table is locked to
+        // insert it. Another thread may have inserted it in the
+        // meantime.
+        let mut chunks = self.chunks.write().unwrap_or_else(PoisonError::into_inner);
+        let chunk = chunks
+            .entry(key)
+            .or_insert_with(|| RwLock::new(new_chunk()))
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        std::mem::replace(&mut chunk[index], h)
+    }
+}
+
+impl<H: Sample> Clone for LockedStorage<H> {
+    fn clone(&self) -> Self {
+        let chunks = self.chunks.read().unwrap_or_else(PoisonError::into_inner);
+        let chunks = chunks
+            .iter()
+            .map(|(&key, chunk)| {
+                let chunk = chunk.read().unwrap_or_else(PoisonError::into_inner).clone();
+
+                (key, RwLock::new(chunk))
+            })
+            .collect();
+
+        Self {
+            chunks: Arc::new(RwLock::new(chunks)),
+        }
+    }
+}
+
+impl<H: Sample> Storage<H> for LockedStorage<H> {
+    fn get(&self, x: i64, y: i64) -> Option<H> {
+        LockedStorage::get(self, x, y)
+    }
+
+    fn set(&mut self, x: i64, y: i64, h: Option<H>) -> Option<H> {
+        LockedStorage::set(self, x, y, h)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Storage<H>>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// This is an enumeration with possible encodings of the
+/// cells of the dense storage, which is created by `Builder`.
+/// The compact encodings are for `f32` heights only, so
+/// `Builder::encoding` is available only for `f32` maps, and
+/// the maps with other sample types always use `DenseStorage`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Encoding {
+    /// `Option<f32>`, 8 bytes per cell (`DenseStorage`).
+    Optional,
+    /// `f32` with NaN for not generated cells, 4 bytes per
+    /// cell (`NanStorage`).
+    Nan,
+    /// `u16` over the range `0.0..=1.0` and an occupancy
+    /// bitmap, a bit more than 2 bytes per cell
+    /// (`QuantizedStorage`).
+    Quantized,
+}
+
+fn chunk_key(x: i64, y: i64) -> (i64, i64) {
+    (x >> CHUNK_SHIFT, y >> CHUNK_SHIFT)
+}
+
+fn chunk_index(x: i64, y: i64) -> usize {
+    let mask = CHUNK_SIZE - 1;
+
+    ((y & mask) * CHUNK_SIZE + (x & mask)) as usize
+}
+
+fn new_chunk<H: Sample>() -> Chunk<H> {
+    vec![None; CHUNK_LEN].into_boxed_slice()
+}
+
+fn dense_len(width: i64, height: i64) -> usize {
+    (width as usize)
+        .checked_mul(height as usize)
+        .expect("the map is too big for the dense storage")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::Builder;
+
+    #[test]
+    fn chunked_allocates_lazily() {
+        let mut cells = ChunkedStorage::new();
+
+        assert_eq!(cells.set(1000, 2000, None), None);
+        assert_eq!(cells.get(1000, 2000), None);
+        assert_eq!(cells.chunks(), 0);
+
+        assert_eq!(cells.set(1000, 2000, Some(0.5)), None);
+        assert_eq!(cells.set(1000, 2000, Some(0.25)), Some(0.5));
+        assert_eq!(cells.get(1000, 2000), Some(0.25));
+        assert_eq!(cells.get(1001, 2000), None);
+        assert!(cells.is_generated(1000, 2000));
+        assert_eq!(cells.chunks(), 1);
+    }
+
+    #[test]
+    fn compact_encodings() {
+        let mut nan = NanStorage::new(9);
+        let mut quantized = QuantizedStorage::new(9, 0.0, 1.0);
+
+        let all: [&mut dyn Storage; 2] = [&mut nan, &mut quantized];
+
+        for cells in all {
+            assert_eq!(cells.get(3, 4), None);
+            assert_eq!(cells.set(3, 4, Some(0.5)), None);
+            assert!((cells.get(3, 4).unwrap() - 0.5).abs() < 1e-4);
+            assert!(cells.set(3, 4, None).is_some());
+            assert_eq!(cells.get(3, 4), None);
+            assert_eq!(cells.get(8, 8), None);
+        }
+
+        quantized.set(8, 8, Some(2.0));
+        assert_eq!(quantized.get(8, 8), Some(1.0));
+    }
+
+    #[test]
+    fn cached_evicts_least_recently_used() {
+        let mut cells = CachedStorage::new(0, 64);
+
+        assert_eq!(cells.max_chunks(), MIN_CACHED_CHUNKS);
+
+        // One tile is taken by the cells on the grid.
+        for i in 0..MIN_CACHED_CHUNKS as i64 - 1 {
+            cells.set(i * CHUNK_SIZE + 1, 1, Some(0.5));
+        }
+
+        assert_eq!(cells.get(1, 1), Some(0.5));
+
+        cells.set(100 * CHUNK_SIZE + 1, 1, Some(0.5));
+
+        assert_eq!(cells.chunks(), MIN_CACHED_CHUNKS - 1);
+        assert_eq!(cells.get(1, 1), Some(0.5));
+        assert_eq!(cells.get(CHUNK_SIZE + 1, 1), None);
+
+        cells.set(0, 0, Some(0.25));
+
+        for i in 0..100 {
+            cells.set(i * CHUNK_SIZE + 1, 1, Some(0.5));
+        }
+
+        assert_eq!(cells.get(0, 0), Some(0.25));
+    }
+
+    #[derive(Debug, Clone)]
+    struct Probe(CachedStorage, Arc<AtomicUsize>);
+
+    impl Storage for Probe {
+        fn get(&self, x: i64, y: i64) -> Option<f32> {
+            self.0.get(x, y)
+        }
+
+        fn set(&mut self, x: i64, y: i64, h: Option<f32>) -> Option<f32> {
+            let old = self.0.set(x, y, h);
+            let cells = self.0.chunks() * CHUNK_LEN + self.0.kept_aside() * 4;
+            self.1.fetch_max(cells, Ordering::Relaxed);
+
+            old
+        }
+    }
+
+    #[test]
+    fn cached_memory_stays_flat() {
+        let size = (1 << 20) + 1;
+        let peak = Arc::new(AtomicUsize::new(0));
+        let cells = CachedStorage::new(8, (size - 1) >> 1);
+        let mut map = Builder::new(size, 0.3)
+            .seed("walk")
+            .storage(Box::new(Probe(cells, Arc::clone(&peak))))
+            .build();
+        let mut chunked = Builder::new(size, 0.3).seed("walk").chunked(true).build();
+
+        for i in 0..1000 {
+            let (x, y) = (i * 1009, i * 197 % size);
+
+            assert_eq!(map.gen(x, y), chunked.gen(x, y));
+        }
+
+        assert!(peak.load(Ordering::Relaxed) <= 8 * CHUNK_LEN);
+    }
+}
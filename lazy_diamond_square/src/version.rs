@@ -0,0 +1,332 @@
+use std::sync::Arc;
+
+use seahash::hash_seeded;
+
+use crate::{Context, RandFn, Sample};
+
+/// This is an enumeration with the versions of the generation
+/// algorithm. A version pins everything the heights depend on
+/// besides the settings of the map: the default `gen_rand_fn`,
+/// the heights set by `init` and the arithmetic of the
+/// generation, so a map built with the same settings and the
+/// same version is the same after the crate is upgraded. A
+/// change of the output is released as a new version.
+///
+/// The default version is `V1`, whose random numbers differ from
+/// the ones of the crate 1.1.0, so the same seed gives another
+/// map than 1.1.0 did. `V0` gives the maps of 1.1.0.
+///
+/// # Examples
+///
+/// ```
+/// use lazy_diamond_square::{Builder, Version};
+///
+/// let mut map = Builder::new(65, 0.15)
+///     .seed("qwerty")
+///     .version(Version::V1)
+///     .build();
+///
+/// assert_eq!(map.version(), Version::V1);
+/// assert!(map.gen(10, 10).is_some());
+/// ```
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+#[non_exhaustive]
+pub enum Version {
+    /// The algorithm of the crate 1.1.0. The random numbers are
+    /// made of the remainders of the coordinates and the seed
+    /// after 80 rounds of sums, the initial heights are the same
+    /// as in `V1`. The square-square subdivision, which 1.1.0 did
+    /// not have, uses the level seeds of `V1`.
+    ///
+    /// The heights are the ones set by `init` of 1.1.0. Its `gen`
+    /// drew the random number of a point at the coordinates of
+    /// one of its parents, so the points, which 1.1.0 generated
+    /// lazily, may differ from the ones generated now.
+    V0,
+    /// The first stable version. The random numbers are
+    /// SplitMix64 hashes of the coordinates and the seed, the
+    /// initial heights are seahash hashes of the decimal
+    /// coordinates.
+    #[default]
+    V1,
+}
+
+impl Version {
+    pub(crate) fn rand_fn<H: Sample>(self) -> Arc<RandFn<H>> {
+        match self {
+            Version::V0 => Arc::new(|c: &Context<H>, seed| legacy_rand(c.index.0, c.index.1, seed)),
+            Version::V1 => Arc::new(|c: &Context<H>, seed| hash_coords(c.index.0, c.index.1, seed)),
+        }
+    }
+
+    /// Returns the height of the corner of a root square set by
+    /// `InitBy::DiamondSquare`.
+    pub(crate) fn corner(self, x: i64, y: i64, seed: u64) -> u16 {
+        match self {
+            Version::V0 | Version::V1 => {
+                hash_keyed((x.to_string() + "_" + &y.to_string()).as_bytes(), seed)
+            }
+        }
+    }
+
+    /// Returns the height of the point with the number `num`
+    /// (in the row-major order) set by `InitBy::Seed`.
+    pub(crate) fn seed_point(self, num: u64, seed: u64) -> u16 {
+        match self {
+            Version::V0 | Version::V1 => hash_keyed(num.to_string().as_bytes(), seed),
+        }
+    }
+
+    /// Returns the seed of the random numbers of the level
+    /// `level` of the square-square subdivision.
+    pub(crate) fn level_seed(self, seed: u64, level: u32) -> u64 {
+        match self {
+            Version::V0 | Version::V1 => hash_coords(level as i64, 0, seed),
+        }
+    }
+}
+
+/// Hashes the bytes with the keys made of the 16-bit parts of
+/// the seed.
+fn hash_keyed(bytes: &[u8], seed: u64) -> u16 {
+    hash_seeded(
+        bytes,
+        seed & 0xFFFF,
+        seed & 0xFFFF0000,
+        seed & 0xFFFF00000000,
+        seed & 0xFFFF000000000000,
+    ) as u16
+}
+
+/// The `gen_rand_fn` of `V0`, as it was in 1.1.0, but with the
+/// sums wrapping on overflow, as they did in release builds.
+fn legacy_rand(x: i64, y: i64, seed: u64) -> u64 {
+    let (mut x, mut y) = (x as u64, y as u64);
+
+    let mut xm7 = x % 7;
+    let mut xm13 = x % 13;
+    let mut xm1301081 = x % 1301081;
+    let mut ym8461 = y % 8461;
+    let mut ym105467 = y % 105467;
+    let mut ym105943 = y % 105943;
+
+    for _ in 0..80 {
+        y = x.wrapping_add(seed);
+        x = x.wrapping_add(xm7 + xm13 + xm1301081 + ym8461 + ym105467 + ym105943);
+        xm7 = x % 7;
+        xm13 = x % 13;
+        xm1301081 = x % 1301081;
+        ym8461 = y % 8461;
+        ym105467 = y % 105467;
+        ym105943 = y % 105943;
+    }
+
+    let sum = xm7 + xm13 + xm1301081 + ym8461 + ym105467 + ym105943;
+
+    seed ^ (sum as f64 / 1520972.0).to_bits()
+}
+
+/// The `gen_rand_fn` of `V1`: the coordinates and the seed are
+/// mixed by the finalizer of SplitMix64, one round per value, so
+/// neighbouring points get unrelated numbers.
+fn hash_coords(x: i64, y: i64, seed: u64) -> u64 {
+    const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+    let mix = |mut z: u64| {
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+
+    let h = mix(seed.wrapping_add(GAMMA));
+    let h = mix(h ^ (x as u64).wrapping_add(GAMMA.wrapping_mul(2)));
+
+    mix(h ^ (y as u64).wrapping_add(GAMMA.wrapping_mul(3)))
+}
+
+#[cfg(test)]
+mod tests {
+    use seahash::hash;
+    use tinyrand::{Rand, Seeded, StdRand};
+
+    use super::*;
+    use crate::{Builder, EdgeMode, Fixed, InitBy};
+
+    /// The random value of the point, as `calc_h` gets it from the
+    /// default `gen_rand_fn`.
+    fn default_rand(x: i64, y: i64, seed: u64) -> u16 {
+        StdRand::seed(hash_coords(x, y, seed)).next_u16()
+    }
+
+    #[test]
+    fn rand_uniform() {
+        for seed in [0, 1, hash(b"rand_uniform")] {
+            let mut buckets = [0u32; 256];
+            let mut ones = [0u32; 64];
+
+            for y in -128..128 {
+                for x in -128..128 {
+                    buckets[(default_rand(x, y, seed) >> 8) as usize] += 1;
+
+                    let h = hash_coords(x, y, seed);
+
+                    for (bit, count) in ones.iter_mut().enumerate() {
+                        *count += (h >> bit) as u32 & 1;
+                    }
+                }
+            }
+
+            // 65536 values in 256 buckets. The critical value of the
+            // chi-squared distribution with 255 degrees of freedom
+            // for p = 0.001 is about 330.5.
+            let expected = 256.0;
+            let chi2: f64 = buckets
+                .iter()
+                .map(|&n| (n as f64 - expected).powi(2) / expected)
+                .sum();
+
+            assert!(chi2 < 330.5, "chi2 = {chi2}");
+
+            // Each bit is set in half of the values, within 5 sigmas.
+            for count in ones {
+                assert!((count as i64 - 32768).abs() < 5 * 128, "{count}");
+            }
+        }
+    }
+
+    #[test]
+    fn rand_axis_correlation() {
+        fn correlation(pairs: &[(f64, f64)]) -> f64 {
+            let n = pairs.len() as f64;
+            let (mean_a, mean_b) = (
+                pairs.iter().map(|p| p.0).sum::<f64>() / n,
+                pairs.iter().map(|p| p.1).sum::<f64>() / n,
+            );
+            let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+
+            for &(a, b) in pairs {
+                cov += (a - mean_a) * (b - mean_b);
+                var_a += (a - mean_a).powi(2);
+                var_b += (b - mean_b).powi(2);
+            }
+
+            cov / (var_a * var_b).sqrt()
+        }
+
+        let seed = hash(b"rand_axis_correlation");
+        let unit = |x: i64, y: i64| default_rand(x, y, seed) as f64 / u16::MAX as f64;
+        let points: Vec<_> = (0..256)
+            .flat_map(|y| (0..256).map(move |x| (x, y)))
+            .collect();
+
+        let cases: [(&str, Vec<(f64, f64)>); 4] = [
+            (
+                "x",
+                points
+                    .iter()
+                    .map(|&(x, y)| (unit(x, y), x as f64))
+                    .collect(),
+            ),
+            (
+                "y",
+                points
+                    .iter()
+                    .map(|&(x, y)| (unit(x, y), y as f64))
+                    .collect(),
+            ),
+            (
+                "x + 1",
+                points
+                    .iter()
+                    .map(|&(x, y)| (unit(x, y), unit(x + 1, y)))
+                    .collect(),
+            ),
+            (
+                "y + 1",
+                points
+                    .iter()
+                    .map(|&(x, y)| (unit(x, y), unit(x, y + 1)))
+                    .collect(),
+            ),
+        ];
+
+        // The standard error of the correlation of 65536 pairs of
+        // independent values is 1 / 256, the bound is 5 of them.
+        for (name, pairs) in cases {
+            let r = correlation(&pairs);
+
+            assert!(r.abs() < 5.0 / 256.0, "{name}: {r}");
+        }
+
+        // The neighbours along an axis must not be the same value.
+        assert_ne!(hash_coords(1, 2, seed), hash_coords(2, 1, seed));
+        assert_ne!(hash_coords(0, 0, seed), hash_coords(0, 0, seed + 1));
+    }
+
+    #[test]
+    fn golden_v0() {
+        // The heights set by `init` of the crate 1.1.0.
+        let builder = || Builder::new(65, 0.3).seed("golden").version(Version::V0);
+        let points = [
+            (0, 0),
+            (32, 32),
+            (17, 3),
+            (63, 50),
+            (1, 1),
+            (64, 64),
+            (5, 60),
+        ];
+        let golden = [
+            1044093116, 1057237579, 1057647599, 1054139257, 1051834121, 1064625909, 1058446750,
+        ];
+
+        let full = builder().init_lvl(6).build();
+        let mut lazy = builder().build();
+
+        assert_eq!(
+            points.map(|(x, y)| full.get(x, y).unwrap().to_bits()),
+            golden
+        );
+        assert_eq!(
+            points.map(|(x, y)| lazy.gen(x, y).unwrap().to_bits()),
+            golden
+        );
+
+        let seeded = builder().init_lvl(3).init_by(InitBy::Seed).build();
+
+        assert_eq!(
+            [(16, 48), (0, 0), (64, 32)].map(|(x, y)| seeded.get(x, y).unwrap().to_bits()),
+            [1061072319, 1063214559, 1061032894]
+        );
+    }
+
+    #[test]
+    fn golden_v1() {
+        let builder = || Builder::new(65, 0.3).seed("golden").version(Version::V1);
+
+        let mut map = builder().build();
+        let heights = [(0, 0), (32, 32), (17, 3), (63, 50), (1, 1)]
+            .map(|(x, y)| map.gen(x, y).unwrap().to_bits());
+
+        assert_eq!(
+            heights,
+            [1044093116, 1060939089, 1057508902, 1061706267, 1055943586]
+        );
+
+        let mut seeded = builder().init_lvl(3).init_by(InitBy::Seed).build();
+        let mut squares = builder().squares(2, 3).edge_mode(EdgeMode::Mirror).build();
+        let mut fixed = Builder::<Fixed>::with_sample(65, 0.3)
+            .seed("golden")
+            .version(Version::V1)
+            .build();
+
+        assert_eq!(seeded.get(16, 48).unwrap().to_bits(), 1061072319);
+        assert_eq!(seeded.gen(5, 7).unwrap().to_bits(), 1055592235);
+        assert_eq!(squares.gen(100, 150).unwrap().to_bits(), 1060108813);
+        assert_eq!(fixed.gen(17, 3), Some(Fixed(34891)));
+
+        let mut square_square = builder().init_by(InitBy::SquareSquare).build();
+        assert_eq!(square_square.gen(17, 3).unwrap().to_bits(), 1055334542);
+        assert_eq!(hash_coords(-5, 7, 42), 5690598791037142045);
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use seahash::{hash, hash_seeded};
+use tinyrand::{Rand, Seeded, StdRand};
+use tinyrand_std::clock_seed::ClockSeed;
+
+use crate::{Builder, HeightMap, InitBy};
+
+/// An infinite height map made of `HeightMap` tiles. Tiles are
+/// addressed by `i64` tile coordinates and are created when a
+/// point in them is first generated. The seed of each tile is
+/// derived from the seed of the world and the tile coordinates.
+///
+/// Neighbouring tiles share their edges: the last column of a
+/// tile is the first column of the next one, so one tile covers
+/// `tile_size() - 1` points of the world along each axis. The
+/// edges are generated by the midpoint displacement from the
+/// world coordinates only, so the same edge gets the same values
+/// in both tiles and the world has no seams. All `i64`
+/// coordinates are valid: the coordinates of the edges of the
+/// last tiles, which stick out of this range, wrap around.
+///
+/// # Examples
+///
+/// ```
+/// use lazy_diamond_square::World;
+///
+/// let mut world = World::new_with_seed(65, 0.15, "qwerty");
+///
+/// assert_eq!(world.get(-1000, 1000), None);
+/// assert_ne!(world.gen(-1000, 1000), None);
+/// assert_eq!(world.get(-1000, 1000), world.gen(-1000, 1000));
+/// ```
+pub struct World {
+    tile_size: i64,
+    roughness: f32,
+    seed: u64,
+    tiles: HashMap<(i64, i64), HeightMap>,
+}
+
+impl World {
+    /// Returns a new instance of the `World` structure. The
+    /// `tile_size` and the `roughness` are changed in the same way
+    /// as in `HeightMap::new`.
+    pub fn new(tile_size: i64, roughness: f32) -> Self {
+        let builder = Builder::new(tile_size, roughness);
+
+        World {
+            tile_size: builder.size,
+            roughness: builder.roughness,
+            seed: StdRand::seed(ClockSeed.next_u64()).next_u64(),
+            tiles: HashMap::new(),
+        }
+    }
+    /// Returns a new seeded instance of the `World` structure.
+    pub fn new_with_seed(tile_size: i64, roughness: f32, seed: &str) -> Self {
+        World {
+            seed: hash(seed.as_bytes()),
+            ..World::new(tile_size, roughness)
+        }
+    }
+    /// Getter for `tile_size` field.
+    pub fn tile_size(&self) -> i64 {
+        self.tile_size
+    }
+    /// Getter for `roughness` field.
+    pub fn roughness(&self) -> f32 {
+        self.roughness
+    }
+    /// Getter for `seed` field.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+    /// Returns the seed of the tile with specified tile
+    /// coordinates.
+    pub fn tile_seed(&self, tx: i64, ty: i64) -> u64 {
+        hash_seeded(b"tile", self.seed(), tx as u64, ty as u64, 0)
+    }
+    /// Returns the coordinates of the tile, which holds the point
+    /// with specified world coordinates, and the coordinates of
+    /// the point in this tile. A point on the edge belongs to the
+    /// tile, for which it is on the left or top edge.
+    pub fn to_tile_coords(&self, x: i64, y: i64) -> ((i64, i64), (i64, i64)) {
+        let side = self.side();
+
+        (
+            (x.div_euclid(side), y.div_euclid(side)),
+            (x.rem_euclid(side), y.rem_euclid(side)),
+        )
+    }
+    /// Returns the tile with specified tile coordinates, if it
+    /// has been already created.
+    pub fn tile(&self, tx: i64, ty: i64) -> Option<&HeightMap> {
+        self.tiles.get(&(tx, ty))
+    }
+    /// Returns the number of created tiles.
+    pub fn tiles(&self) -> usize {
+        self.tiles.len()
+    }
+    /// Returns the height value at specified world coordinates.
+    pub fn get(&self, x: i64, y: i64) -> Option<f32> {
+        let ((tx, ty), (x, y)) = self.to_tile_coords(x, y);
+
+        self.tile(tx, ty).and_then(|tile| tile.get(x, y))
+    }
+    /// Generates the height value at specified world coordinates
+    /// if it is not generated yet, creating the tile if needed,
+    /// and returns it.
+    pub fn gen(&mut self, x: i64, y: i64) -> Option<f32> {
+        let ((tx, ty), (x, y)) = self.to_tile_coords(x, y);
+
+        self.tile_mut(tx, ty).gen(x, y)
+    }
+    /// Returns a vector of the results of calls to the `get`
+    /// method for each point on the specified area.
+    pub fn get_area(
+        &self,
+        top_left: (i64, i64),
+        bottom_right: (i64, i64),
+    ) -> Vec<(i64, i64, Option<f32>)> {
+        let mut area = Vec::new();
+
+        for y in top_left.1..bottom_right.1 {
+            for x in top_left.0..bottom_right.0 {
+                area.push((x, y, self.get(x, y)));
+            }
+        }
+
+        area
+    }
+    /// Returns a vector of the results of calls to the `gen`
+    /// method for each point on the specified area.
+    pub fn gen_area(
+        &mut self,
+        top_left: (i64, i64),
+        bottom_right: (i64, i64),
+    ) -> Vec<(i64, i64, Option<f32>)> {
+        let mut area = Vec::new();
+
+        for y in top_left.1..bottom_right.1 {
+            for x in top_left.0..bottom_right.0 {
+                area.push((x, y, self.gen(x, y)));
+            }
+        }
+
+        area
+    }
+
+    fn side(&self) -> i64 {
+        self.tile_size() - 1
+    }
+
+    fn tile_mut(&mut self, tx: i64, ty: i64) -> &mut HeightMap {
+        if !self.tiles.contains_key(&(tx, ty)) {
+            let tile = self.new_tile(tx, ty);
+            self.tiles.insert((tx, ty), tile);
+        }
+
+        self.tiles.get_mut(&(tx, ty)).unwrap()
+    }
+
+    fn new_tile(&self, tx: i64, ty: i64) -> HeightMap {
+        let mut tile = Builder {
+            seed: self.tile_seed(tx, ty),
+            ..Builder::new(self.tile_size(), self.roughness()).init_by(InitBy::None)
+        }
+        .build();
+
+        let side = self.side();
+        // The last tiles stick out of the `i64` range, so their
+        // edges wrap around.
+        let (left, top) = (tx.wrapping_mul(side), ty.wrapping_mul(side));
+        let max_coord = tile.max_coord();
+
+        let edges = [
+            ((left, top), (0, 0), (1, 0)),
+            ((left, top.wrapping_add(side)), (0, max_coord), (1, 0)),
+            ((left, top), (0, 0), (0, 1)),
+            ((left.wrapping_add(side), top), (max_coord, 0), (0, 1)),
+        ];
+
+        for (from, local, dir) in edges {
+            for (i, h) in self.gen_edge(from, dir).into_iter().enumerate() {
+                let i = i as i64;
+
+                tile.set(local.0 + dir.0 * i, local.1 + dir.1 * i, Some(h));
+            }
+        }
+
+        tile
+    }
+
+    /// Generates the edge of a tile, which starts at the world
+    /// point `from` and goes in the direction `dir`, by the
+    /// midpoint displacement.
+    fn gen_edge(&self, from: (i64, i64), dir: (i64, i64)) -> Vec<f32> {
+        let size = self.tile_size() as usize;
+        let point = |i: usize| {
+            let i = i as i64;
+
+            (
+                from.0.wrapping_add(dir.0 * i),
+                from.1.wrapping_add(dir.1 * i),
+            )
+        };
+
+        let mut edge = vec![0.0; size];
+        edge[0] = HeightMap::to_range(0.0, 1.0, self.rand(point(0)));
+        edge[size - 1] = HeightMap::to_range(0.0, 1.0, self.rand(point(size - 1)));
+
+        let mut step = (size - 1) >> 1;
+
+        while step > 0 {
+            for i in (step..size - 1).step_by(step << 1) {
+                let h = (edge[i - step] + edge[i + step]) / 2.0;
+                let rand = HeightMap::to_range(0.0, 1.0, self.rand(point(i)));
+
+                edge[i] = self.roughness() * rand + (1.0 - self.roughness()) * h;
+            }
+
+            step >>= 1;
+        }
+
+        edge
+    }
+
+    fn rand(&self, (x, y): (i64, i64)) -> u16 {
+        StdRand::seed(hash_seeded(b"edge", self.seed(), x as u64, y as u64, 1)).next_u16()
+    }
+}
+
+impl fmt::Debug for World {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("World")
+            .field("tile_size", &self.tile_size)
+            .field("roughness", &self.roughness)
+            .field("seed", &self.seed)
+            .field("tiles", &self.tiles.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::assert_gen_reversed;
+
+    #[test]
+    fn seamless() {
+        let mut world = World::new_with_seed(17, 0.15, "seamless");
+        let side = world.tile_size() - 1;
+
+        world.gen_area((-side, -side), (side, side));
+
+        assert_eq!(world.tiles(), 4);
+
+        for i in 0..=side {
+            let left = world.tile(-1, 0).unwrap().get(side, i);
+            let right = world.tile(0, 0).unwrap().get(0, i);
+            let top = world.tile(0, -1).unwrap().get(i, side);
+            let bottom = world.tile(0, 0).unwrap().get(i, 0);
+
+            assert!(left.is_some() && top.is_some());
+            assert_eq!(left, right);
+            assert_eq!(top, bottom);
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let mut a = World::new_with_seed(17, 0.15, "deterministic");
+        let mut b = World::new_with_seed(17, 0.15, "deterministic");
+
+        let area = a.gen_area((-40, -40), (40, 40));
+
+        assert_gen_reversed(&area, |x, y| b.gen(x, y));
+    }
+
+    #[test]
+    fn extreme_coords() {
+        let mut world = World::new_with_seed(17, 0.2, "x");
+        let ((tx, _), _) = world.to_tile_coords(i64::MAX, 0);
+
+        assert_ne!(world.gen(i64::MAX, 0), None);
+        assert_ne!(world.gen(i64::MIN, i64::MIN), None);
+        assert_ne!(world.gen(i64::MAX, i64::MAX), None);
+
+        // The tile before the last one shares its edge.
+        world.gen(tx * 16 - 1, 0);
+
+        for i in 0..=16 {
+            let left = world.tile(tx - 1, 0).unwrap().get(16, i);
+
+            assert_eq!(left, world.tile(tx, 0).unwrap().get(0, i));
+        }
+    }
+}